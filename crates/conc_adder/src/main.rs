@@ -1,13 +1,10 @@
 use futures::executor::block_on;
 use rayon::prelude::*;
 use std::{
-    sync::{
-        mpsc::{self, Sender},
-        Arc, Mutex,
-    },
-    thread::{self, JoinHandle},
+    sync::{Arc, Mutex},
     time::Instant,
 };
+use threadpool::ThreadPool;
 
 type Ival = i64;
 
@@ -24,70 +21,39 @@ macro_rules! time_it {
     };
 }
 
-type Job = Box<dyn FnOnce() + Send + 'static>;
-
-#[derive(Debug)]
-struct ThreadPool {
-    threads: Vec<JoinHandle<()>>,
-    sender: Option<Sender<Job>>,
-}
-
-impl ThreadPool {
-    fn new(cap: usize) -> Self {
-        assert_ne!(cap, 0);
-        let (sender, receiver) = mpsc::channel::<Job>();
-        let receiver_mutex = Arc::new(Mutex::new(receiver));
-        let mut threads: Vec<JoinHandle<()>> = Vec::with_capacity(cap);
-        for _id in 0..cap {
-            let receiver_clone = Arc::clone(&receiver_mutex);
-            threads.push(thread::spawn(move || loop {
-                let job = receiver_clone.lock().unwrap().recv();
-                if let Ok(_job) = job {
-                    _job();
-                } else {
-                    return;
-                }
-            }));
-        }
-        ThreadPool {
-            threads,
-            sender: Some(sender),
-        }
-    }
-
-    fn add_job<F>(&self, job: F)
-    where
-        F: FnOnce() + Send + 'static,
-    {
-        self.sender.as_ref().unwrap().send(Box::new(job)).unwrap();
-    }
-}
-
-impl Drop for ThreadPool {
-    fn drop(&mut self) {
-        self.sender = None;
-        while let Some(thread) = self.threads.pop() {
-            thread.join().unwrap();
-        }
-    }
-}
-
 fn adder_thread(counter_mutex_arc: Arc<Mutex<Ival>>, num: Ival) {
     *counter_mutex_arc.lock().unwrap() += num;
 }
 
 fn add_using_threads(pool_size: usize, nums: &Vec<Ival>) -> Ival {
     let counter_arc_mutex = Arc::new(Mutex::new(0));
-    let pool: ThreadPool = ThreadPool::new(pool_size);
+    let pool: ThreadPool = ThreadPool::with_capacity(pool_size);
     for num in nums {
         let counter_clone = Arc::clone(&counter_arc_mutex);
         let num_clone = *num;
-        pool.add_job(move || adder_thread(counter_clone, num_clone))
+        pool.execute(move || adder_thread(counter_clone, num_clone))
+            .unwrap();
     }
-    drop(pool);
+    pool.shutdown();
     return *counter_arc_mutex.lock().unwrap();
 }
 
+// Jobs borrow slices of `nums` instead of taking a counter and a number each.
+fn add_using_scoped_threads(pool_size: usize, nums: &[Ival]) -> Ival {
+    let pool: ThreadPool = ThreadPool::with_capacity(pool_size);
+    let chunk_size = nums.len().div_ceil(pool_size).max(1);
+    pool.scope(|scope| {
+        let handles: Vec<_> = nums
+            .chunks(chunk_size)
+            .map(|chunk| scope.spawn(move || chunk.iter().sum::<Ival>()).unwrap())
+            .collect();
+        handles
+            .into_iter()
+            .map(|handle| handle.join().unwrap())
+            .sum()
+    })
+}
+
 fn add_using_rayon(nums: &Vec<Ival>) -> Ival {
     nums.par_iter().sum()
 }
@@ -120,6 +86,10 @@ fn main() {
             format!("Threads with pool-size of {}", ps),
             add_using_threads(ps as usize, &nums)
         );
+        time_it!(
+            format!("Scoped threads with pool-size of {}", ps),
+            add_using_scoped_threads(ps as usize, &nums)
+        );
     }
     time_it!("Async", add_using_async(&nums));
 }
@@ -0,0 +1,85 @@
+//! Compares transfer strategies of the `cprs` binary on a deep and a wide tree.
+//!
+//! Trees are generated under `CPRS_BENCH_SOURCE` and copied to `CPRS_BENCH_DEST`, both default to the temp dir. Point
+//! them to different disks to compare copies across devices, for example an SSD to an HDD -
+//! `CPRS_BENCH_SOURCE=/mnt/ssd/tmp CPRS_BENCH_DEST=/mnt/hdd/tmp cargo bench -p cprs`
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    time::{Duration, Instant},
+};
+
+const FILE_SIZE: usize = 1 << 14;
+const RUNS: usize = 3;
+const STRATEGIES: [&[&str]; 5] = [
+    &["-s", "serial"],
+    &["-s", "rayon"],
+    &["-s", "threadpool"],
+    &["-s", "threadpool", "-j", "16"],
+    &["-s", "threadpool", "-j", "16", "--dir-workers", "4"],
+];
+
+fn bench_dir(var: &str) -> PathBuf {
+    env::var(var)
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| env::temp_dir())
+        .join("cprs-bench")
+}
+
+/// Writes `files` files in `dir` and recurses into `branches` subdirectories till `depth` is 0.
+fn generate(dir: &Path, depth: u32, branches: u32, files: u32) {
+    fs::create_dir_all(dir).unwrap();
+    let data = vec![b'x'; FILE_SIZE];
+    for i in 0..files {
+        fs::write(dir.join(format!("file_{i}")), &data).unwrap();
+    }
+    if depth == 0 {
+        return;
+    }
+    for i in 0..branches {
+        generate(&dir.join(format!("dir_{i}")), depth - 1, branches, files);
+    }
+}
+
+fn run(source: &Path, dest: &Path, args: &[&str]) -> Duration {
+    let mut times = (0..RUNS)
+        .map(|_| {
+            let _ = fs::remove_dir_all(dest);
+            fs::create_dir_all(dest).unwrap();
+            let start = Instant::now();
+            let status = Command::new(env!("CARGO_BIN_EXE_cprs"))
+                .arg(source)
+                .arg(dest)
+                .args(args)
+                .stdin(Stdio::null())
+                .stdout(Stdio::null())
+                .status()
+                .unwrap();
+            assert!(status.success(), "cprs failed with {args:?}");
+            start.elapsed()
+        })
+        .collect::<Vec<Duration>>();
+    times.sort();
+    times[RUNS / 2]
+}
+
+fn main() {
+    let (source_root, dest_root) = (
+        bench_dir("CPRS_BENCH_SOURCE"),
+        bench_dir("CPRS_BENCH_DEST").join("out"),
+    );
+    // Both trees have 2046 files, deep one spreads them over 1023 directories and wide one over 2.
+    let trees = [("deep", 9, 2, 2), ("wide", 1, 1, 1023)];
+    for (name, depth, branches, files) in trees {
+        let source = source_root.join(name);
+        let _ = fs::remove_dir_all(&source);
+        generate(&source, depth, branches, files);
+        for args in STRATEGIES {
+            let median = run(&source, &dest_root, args);
+            println!("{name:<5} {:<40} {median:>10.2?}", args.join(" "));
+        }
+    }
+    let _ = fs::remove_dir_all(&source_root);
+    let _ = fs::remove_dir_all(&dest_root);
+}
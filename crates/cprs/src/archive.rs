@@ -0,0 +1,502 @@
+//! Tar and zip archives as the destination or the source of a transfer.
+//!
+//! Archives are single streams, so paths are written to and read from them one after another whatever the strategy.
+//! Tar archives can be compressed with gzip or zstd, the format is picked from the extension of the archive path.
+use std::{
+    fs,
+    io::{self, BufReader, BufWriter, Read, Write},
+    os::unix::fs::MetadataExt,
+    path::{Component, Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+use win_tree::{NodeType, TreeNode};
+
+/// Zstd level used by its command line tool by default.
+const ZSTD_LEVEL: i32 = 3;
+
+/// Represents the format of an archive.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Tar,
+    TarGz,
+    TarZstd,
+    Zip,
+}
+
+impl FromStr for Format {
+    fn from_str(format: &str) -> Result<Self, Self::Err> {
+        match format {
+            "tar" => Ok(Self::Tar),
+            "tar.gz" | "tgz" => Ok(Self::TarGz),
+            "tar.zst" | "tzst" => Ok(Self::TarZstd),
+            "zip" => Ok(Self::Zip),
+            _ => Err(String::from("invalid archive format")),
+        }
+    }
+
+    type Err = String;
+}
+
+impl Format {
+    /// Format of the archive at `path` from its extension, `None` if it is not an archive.
+    pub fn detect(path: &str) -> Option<Self> {
+        let name = Path::new(path)
+            .file_name()?
+            .to_string_lossy()
+            .to_lowercase();
+        ["tar.gz", "tar.zst", "tgz", "tzst", "tar", "zip"]
+            .into_iter()
+            .find(|extension| name.ends_with(&format!(".{extension}")))
+            .and_then(|extension| Self::from_str(extension).ok())
+    }
+}
+
+/// Writes entries of an archive into the file it is created with.
+pub enum Writer<'a> {
+    Tar(tar::Builder<BufWriter<&'a mut fs::File>>),
+    TarGz(tar::Builder<GzEncoder<BufWriter<&'a mut fs::File>>>),
+    TarZstd(tar::Builder<zstd::Encoder<'static, BufWriter<&'a mut fs::File>>>),
+    Zip(zip::ZipWriter<BufWriter<&'a mut fs::File>>),
+}
+
+impl<'a> Writer<'a> {
+    pub fn new(file: &'a mut fs::File, format: Format) -> io::Result<Self> {
+        let file = BufWriter::new(file);
+        Ok(match format {
+            Format::Tar => Writer::Tar(tar::Builder::new(file)),
+            Format::TarGz => Writer::TarGz(tar::Builder::new(GzEncoder::new(
+                file,
+                Compression::default(),
+            ))),
+            Format::TarZstd => {
+                Writer::TarZstd(tar::Builder::new(zstd::Encoder::new(file, ZSTD_LEVEL)?))
+            }
+            Format::Zip => Writer::Zip(zip::ZipWriter::new(file)),
+        })
+    }
+
+    pub fn add_dir(&mut self, name: &str, metadata: &fs::Metadata) -> io::Result<()> {
+        match self {
+            Writer::Tar(builder) => tar_entry(builder, name, metadata, io::empty()),
+            Writer::TarGz(builder) => tar_entry(builder, name, metadata, io::empty()),
+            Writer::TarZstd(builder) => tar_entry(builder, name, metadata, io::empty()),
+            Writer::Zip(zip) => Ok(zip.add_directory(name, zip_options(metadata))?),
+        }
+    }
+
+    /// Adds a file of `metadata.len()` bytes read from `data`, which must have exactly that many bytes.
+    pub fn add_file(
+        &mut self,
+        name: &str,
+        metadata: &fs::Metadata,
+        data: &mut dyn Read,
+    ) -> io::Result<()> {
+        // Tar header is written before the data, so the data can neither be shorter nor longer than it says.
+        let data = Exact {
+            inner: data,
+            remaining: metadata.len(),
+        };
+        match self {
+            Writer::Tar(builder) => tar_entry(builder, name, metadata, data),
+            Writer::TarGz(builder) => tar_entry(builder, name, metadata, data),
+            Writer::TarZstd(builder) => tar_entry(builder, name, metadata, data),
+            Writer::Zip(zip) => {
+                zip.start_file(name, zip_options(metadata))?;
+                io::copy(&mut { data }, zip)?;
+                Ok(())
+            }
+        }
+    }
+
+    pub fn add_symlink(
+        &mut self,
+        name: &str,
+        metadata: &fs::Metadata,
+        target: &Path,
+    ) -> io::Result<()> {
+        match self {
+            Writer::Tar(builder) => {
+                tar_link(builder, name, metadata, tar::EntryType::Symlink, target)
+            }
+            Writer::TarGz(builder) => {
+                tar_link(builder, name, metadata, tar::EntryType::Symlink, target)
+            }
+            Writer::TarZstd(builder) => {
+                tar_link(builder, name, metadata, tar::EntryType::Symlink, target)
+            }
+            Writer::Zip(zip) => {
+                Ok(zip.add_symlink(name, target.to_string_lossy(), zip_options(metadata))?)
+            }
+        }
+    }
+
+    /// Whether files can be added as hard links of earlier ones, which zip can not do.
+    pub fn keeps_hard_links(&self) -> bool {
+        !matches!(self, Writer::Zip(_))
+    }
+
+    /// Adds a file as a hard link of the earlier entry named `original`.
+    pub fn add_hard_link(
+        &mut self,
+        name: &str,
+        metadata: &fs::Metadata,
+        original: &str,
+    ) -> io::Result<()> {
+        let original = Path::new(original);
+        match self {
+            Writer::Tar(builder) => {
+                tar_link(builder, name, metadata, tar::EntryType::Link, original)
+            }
+            Writer::TarGz(builder) => {
+                tar_link(builder, name, metadata, tar::EntryType::Link, original)
+            }
+            Writer::TarZstd(builder) => {
+                tar_link(builder, name, metadata, tar::EntryType::Link, original)
+            }
+            Writer::Zip(_) => Err(io::ErrorKind::Unsupported.into()),
+        }
+    }
+
+    /// Writes the end of the archive and of its compression, none of which happens if the writer is just dropped.
+    pub fn finish(self) -> io::Result<()> {
+        match self {
+            Writer::Tar(builder) => builder.into_inner()?.flush(),
+            Writer::TarGz(builder) => builder.into_inner()?.finish()?.flush(),
+            Writer::TarZstd(builder) => builder.into_inner()?.finish()?.flush(),
+            Writer::Zip(zip) => zip.finish()?.flush(),
+        }
+    }
+}
+
+fn tar_entry<W: Write>(
+    builder: &mut tar::Builder<W>,
+    name: &str,
+    metadata: &fs::Metadata,
+    data: impl Read,
+) -> io::Result<()> {
+    let mut header = tar::Header::new_gnu();
+    header.set_metadata_in_mode(metadata, tar::HeaderMode::Complete);
+    builder.append_data(&mut header, name, data)
+}
+
+fn tar_link<W: Write>(
+    builder: &mut tar::Builder<W>,
+    name: &str,
+    metadata: &fs::Metadata,
+    entry_type: tar::EntryType,
+    target: &Path,
+) -> io::Result<()> {
+    let mut header = tar::Header::new_gnu();
+    header.set_metadata_in_mode(metadata, tar::HeaderMode::Complete);
+    header.set_entry_type(entry_type);
+    header.set_size(0);
+    builder.append_link(&mut header, name, target)
+}
+
+fn zip_options(metadata: &fs::Metadata) -> zip::write::SimpleFileOptions {
+    let options = zip::write::SimpleFileOptions::default()
+        .compression_method(zip::CompressionMethod::Deflated)
+        .unix_permissions(metadata.mode() & 0o7777)
+        .large_file(metadata.len() >= u32::MAX as u64);
+    match metadata.modified().ok().and_then(zip_time) {
+        Some(modified) => options.last_modified_time(modified),
+        None => options,
+    }
+}
+
+/// Zip stores local time without a zone, which is what the zip tools do as well.
+fn zip_time(modified: SystemTime) -> Option<zip::DateTime> {
+    let secs = modified.duration_since(UNIX_EPOCH).ok()?.as_secs() as libc::time_t;
+    // SAFETY: `tm` is plain data for which zeroes are valid, `localtime_r` only writes into it.
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    // SAFETY: both pointers are valid through the call.
+    if unsafe { libc::localtime_r(&secs, &mut tm) }.is_null() {
+        return None;
+    }
+    zip::DateTime::from_date_and_time(
+        (tm.tm_year + 1900) as u16,
+        (tm.tm_mon + 1) as u8,
+        tm.tm_mday as u8,
+        tm.tm_hour as u8,
+        tm.tm_min as u8,
+        tm.tm_sec as u8,
+    )
+    .ok()
+}
+
+fn system_time(modified: zip::DateTime) -> Option<SystemTime> {
+    // SAFETY: `tm` is plain data for which zeroes are valid.
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    tm.tm_year = modified.year() as i32 - 1900;
+    tm.tm_mon = modified.month() as i32 - 1;
+    tm.tm_mday = modified.day() as i32;
+    tm.tm_hour = modified.hour() as i32;
+    tm.tm_min = modified.minute() as i32;
+    tm.tm_sec = modified.second() as i32;
+    tm.tm_isdst = -1;
+    // SAFETY: `tm` is valid through the call.
+    let secs = unsafe { libc::mktime(&mut tm) };
+    (secs >= 0).then(|| UNIX_EPOCH + Duration::from_secs(secs as u64))
+}
+
+/// Reads exactly `remaining` bytes from `inner`, ending early is an error.
+struct Exact<'a> {
+    inner: &'a mut dyn Read,
+    remaining: u64,
+}
+
+impl Read for Exact<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.remaining == 0 {
+            // Bytes past the length the header was written with would be cut off silently.
+            return match self.inner.read(&mut [0])? {
+                0 => Ok(0),
+                _ => Err(io::Error::other("file changed size while copying")),
+            };
+        }
+        let len = buf.len().min(self.remaining as usize);
+        let n = self.inner.read(&mut buf[..len])?;
+        if n == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "file got shorter while archiving",
+            ));
+        }
+        self.remaining -= n as u64;
+        Ok(n)
+    }
+}
+
+/// Entry read from an archive.
+pub struct Entry<'a> {
+    /// Name as it is stored in the archive.
+    pub name: String,
+    /// Path relative to the directory the archive is extracted into, `None` if the name would escape it.
+    pub path: Option<PathBuf>,
+    /// Hard links of tar archives are files with a `link`, their device nodes are `Special`.
+    pub node_type: NodeType,
+    pub size: u64,
+    pub mode: Option<u32>,
+    pub modified: Option<SystemTime>,
+    /// Target of a symlink, or the name of the earlier entry a hard link is of.
+    pub link: Option<PathBuf>,
+    pub data: &'a mut dyn Read,
+}
+
+/// Reads entries of the archive at `path` one after another, data of an entry is skipped if `on_entry` does not read
+/// it. Reading stops at the first error from `on_entry`.
+pub fn read(
+    path: &Path,
+    format: Format,
+    on_entry: &mut dyn FnMut(Entry<'_>) -> io::Result<()>,
+) -> io::Result<()> {
+    let file = BufReader::new(fs::File::open(path)?);
+    let reader: Box<dyn Read> = match format {
+        Format::Tar => Box::new(file),
+        Format::TarGz => Box::new(GzDecoder::new(file)),
+        Format::TarZstd => Box::new(zstd::Decoder::with_buffer(file)?),
+        Format::Zip => return read_zip(file, on_entry),
+    };
+    let mut archive = tar::Archive::new(reader);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let header = entry.header();
+        let node_type = match header.entry_type() {
+            tar::EntryType::Regular | tar::EntryType::Continuous | tar::EntryType::Link => {
+                NodeType::File
+            }
+            tar::EntryType::Directory => NodeType::Directory,
+            tar::EntryType::Symlink => NodeType::Symlink,
+            _ => NodeType::Special,
+        };
+        let (size, mode) = (header.size()?, header.mode().ok());
+        let modified = header
+            .mtime()
+            .ok()
+            .map(|mtime| UNIX_EPOCH + Duration::from_secs(mtime));
+        let name = entry.path()?.to_string_lossy().to_string();
+        let link = entry.link_name()?.map(|link| link.to_path_buf());
+        on_entry(Entry {
+            path: enclosed(Path::new(&name)),
+            name,
+            node_type,
+            size,
+            mode,
+            modified,
+            link,
+            data: &mut entry,
+        })?;
+    }
+    Ok(())
+}
+
+fn read_zip(
+    file: BufReader<fs::File>,
+    on_entry: &mut dyn FnMut(Entry<'_>) -> io::Result<()>,
+) -> io::Result<()> {
+    let mut archive = zip::ZipArchive::new(file)?;
+    for index in 0..archive.len() {
+        let mut file = archive.by_index(index)?;
+        let node_type = if file.is_dir() {
+            NodeType::Directory
+        } else if file.is_symlink() {
+            NodeType::Symlink
+        } else {
+            NodeType::File
+        };
+        // Zip stores the target of a symlink as its data.
+        let link = if node_type == NodeType::Symlink {
+            let mut target = String::new();
+            file.read_to_string(&mut target)?;
+            Some(PathBuf::from(target))
+        } else {
+            None
+        };
+        let (name, path) = (file.name().to_string(), file.enclosed_name());
+        let (size, mode) = (file.size(), file.unix_mode());
+        let modified = file.last_modified().and_then(system_time);
+        on_entry(Entry {
+            name,
+            path,
+            node_type,
+            size,
+            mode,
+            modified,
+            link,
+            data: &mut file,
+        })?;
+    }
+    Ok(())
+}
+
+/// `path` if it stays inside the directory it is joined to, which is not the case for absolute paths or ones going up.
+pub fn enclosed(path: &Path) -> Option<PathBuf> {
+    let mut enclosed = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => enclosed.push(name),
+            Component::CurDir => {}
+            Component::ParentDir | Component::RootDir | Component::Prefix(_) => return None,
+        }
+    }
+    (!enclosed.as_os_str().is_empty()).then_some(enclosed)
+}
+
+/// `dir` joined with `path`, which has to be enclosed. Directories of `path` which are already there must not be
+/// symlinks. An archive can hold a symlink to anywhere followed by paths inside of it, which would otherwise be written
+/// through it, so those are refused the same as `tar` does.
+pub fn unpack_path(dir: &Path, path: &Path) -> io::Result<PathBuf> {
+    let mut unpacked = dir.to_path_buf();
+    let mut components = path.components().peekable();
+    while let Some(component) = components.next() {
+        unpacked.push(component);
+        if components.peek().is_none() {
+            break;
+        }
+        match fs::symlink_metadata(&unpacked) {
+            Ok(metadata) if metadata.is_symlink() => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("`{}` is a symlink", unpacked.display()),
+                ))
+            }
+            Ok(_) => {}
+            // Rest of the directories are created fresh.
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                unpacked.extend(components);
+                break;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(unpacked)
+}
+
+/// Lists the archive at `path` as a tree, the root of which is the directory it is extracted into and is named after
+/// the archive. Directories missing from the archive are added for the paths inside them.
+pub fn tree(path: &Path, format: Format) -> io::Result<TreeNode> {
+    #[derive(Default)]
+    struct Listed {
+        node_type: Option<NodeType>,
+        size: u64,
+        children: std::collections::BTreeMap<String, Listed>,
+    }
+
+    impl Listed {
+        fn into_node(self, name: String) -> TreeNode {
+            let node_type = match self.node_type {
+                _ if !self.children.is_empty() => NodeType::Directory,
+                Some(node_type) => node_type,
+                None => NodeType::Directory,
+            };
+            let children = self
+                .children
+                .into_iter()
+                .map(|(name, child)| Arc::new(child.into_node(name)))
+                .collect::<Vec<Arc<TreeNode>>>();
+            let size = match node_type {
+                NodeType::Directory => children.iter().filter_map(|c| c.size_in_bytes).sum(),
+                _ => self.size,
+            };
+            TreeNode {
+                name,
+                is_file: node_type != NodeType::Directory,
+                node_type,
+                size_in_bytes: Some(size),
+                children,
+            }
+        }
+    }
+
+    let mut root = Listed::default();
+    read(path, format, &mut |entry| {
+        // Escaping entries are not extracted, they are still listed at the root so that their failure is counted.
+        let components = match &entry.path {
+            Some(path) => path
+                .components()
+                .map(|c| c.as_os_str().to_string_lossy().to_string())
+                .collect::<Vec<String>>(),
+            None => vec![entry.name.clone()],
+        };
+        let mut listed = &mut root;
+        for component in components {
+            listed = listed.children.entry(component).or_default();
+        }
+        listed.node_type = Some(entry.node_type);
+        listed.size = match entry.node_type {
+            NodeType::File => entry.size,
+            _ => 0,
+        };
+        Ok(())
+    })?;
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    Ok(root.into_node(name.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_are_detected_and_escaping_paths_rejected() {
+        assert_eq!(Format::detect("/backup/home.tar.gz"), Some(Format::TarGz));
+        assert_eq!(Format::detect("home.TZST"), Some(Format::TarZstd));
+        assert_eq!(Format::detect("home.zip"), Some(Format::Zip));
+        assert_eq!(Format::detect("home.tar"), Some(Format::Tar));
+        assert_eq!(Format::detect("home.gz"), None);
+        assert_eq!(Format::detect("tar"), None);
+
+        assert_eq!(
+            enclosed(Path::new("./a/b/../c")),
+            None,
+            "going up is rejected even when it stays inside"
+        );
+        assert_eq!(enclosed(Path::new("./a/b")), Some(PathBuf::from("a/b")));
+        assert_eq!(enclosed(Path::new("/etc/passwd")), None);
+        assert_eq!(enclosed(Path::new(".")), None);
+    }
+}
@@ -0,0 +1,126 @@
+//! Atomic writes of destination files.
+//!
+//! Files are written to a hidden temp file next to their destination, synced and then renamed over it, after which
+//! the directory is synced for the rename to last. A destination file is thus either its old version or the complete
+//! new one, a run killed midway leaves only temp files behind which the next run removes.
+use std::{
+    collections::hash_map::DefaultHasher,
+    ffi::OsString,
+    fs,
+    hash::{Hash, Hasher},
+    io,
+    os::unix::ffi::{OsStrExt, OsStringExt},
+    path::{Path, PathBuf},
+};
+
+const TEMP_SUFFIX: &str = ".cprs-tmp";
+/// Longest file name most file systems take, in bytes.
+const NAME_MAX: usize = 255;
+
+/// Temp path for `dest`, which is `.<name>.cprs-tmp` in the same directory so that the rename does not cross devices.
+/// Names too long for that are cut and a hash of the whole name is added to keep them apart.
+pub fn temp_path(dest: &Path) -> PathBuf {
+    let name = dest.file_name().unwrap_or_default().as_bytes();
+    let mut temp = vec![b'.'];
+    if 1 + name.len() + TEMP_SUFFIX.len() <= NAME_MAX {
+        temp.extend_from_slice(name);
+    } else {
+        let mut hasher = DefaultHasher::new();
+        name.hash(&mut hasher);
+        let hash = format!("-{:016x}", hasher.finish());
+        let kept = NAME_MAX - 1 - hash.len() - TEMP_SUFFIX.len();
+        temp.extend_from_slice(&name[..kept]);
+        temp.extend_from_slice(hash.as_bytes());
+    }
+    temp.extend_from_slice(TEMP_SUFFIX.as_bytes());
+    dest.with_file_name(OsString::from_vec(temp))
+}
+
+fn is_temp(name: &str) -> bool {
+    name.starts_with('.') && name.ends_with(TEMP_SUFFIX) && name.len() > TEMP_SUFFIX.len() + 1
+}
+
+/// Writes `dest` through a temp file, `write` gets the empty temp file. Temp file is removed if anything fails.
+pub fn write(dest: &Path, write: impl FnOnce(&mut fs::File) -> io::Result<()>) -> io::Result<()> {
+    let temp_path = temp_path(dest);
+    let result = fs::File::create(&temp_path).and_then(|mut temp| {
+        write(&mut temp)?;
+        temp.sync_all()?;
+        fs::rename(&temp_path, dest)?;
+        sync_dir(dest)
+    });
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+    result
+}
+
+/// Syncs the directory of `path`, which makes a rename in it durable.
+fn sync_dir(path: &Path) -> io::Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    fs::File::open(dir)?.sync_all()
+}
+
+/// Removes temp files left in `dir` and its subdirectories by an interrupted run, `on_removed` is called with each.
+pub fn remove_stale(dir: &Path, on_removed: &mut dyn FnMut(&Path)) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            remove_stale(&entry.path(), on_removed)?;
+        } else if file_type.is_file() && is_temp(&entry.file_name().to_string_lossy()) {
+            fs::remove_file(entry.path())?;
+            on_removed(&entry.path());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn failed_write_keeps_dest_and_stale_temps_are_removed() {
+        let dir = std::env::temp_dir().join(format!("cprs-atomic-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        let dest = dir.join("file");
+        fs::write(&dest, b"old").unwrap();
+
+        let result = write(&dest, |temp| {
+            io::Write::write_all(temp, b"partial")?;
+            Err(io::Error::other("killed"))
+        });
+        assert!(result.is_err());
+        assert_eq!(fs::read(&dest).unwrap(), b"old");
+        assert!(!temp_path(&dest).exists());
+
+        write(&dest, |temp| io::Write::write_all(temp, b"new")).unwrap();
+        assert_eq!(fs::read(&dest).unwrap(), b"new");
+
+        // Longest names a file system takes still get a temp file of their own.
+        let long = [
+            dir.join("é".repeat(127) + "a"),
+            dir.join("é".repeat(127) + "b"),
+        ];
+        assert_ne!(temp_path(&long[0]), temp_path(&long[1]));
+        for dest in &long {
+            assert!(temp_path(dest).file_name().unwrap().len() <= NAME_MAX);
+            write(dest, |temp| io::Write::write_all(temp, b"long")).unwrap();
+            assert_eq!(fs::read(dest).unwrap(), b"long");
+        }
+
+        let stale = temp_path(&dir.join("sub").join("other"));
+        fs::write(&stale, b"partial").unwrap();
+        fs::write(dir.join(".cprs-tmp"), b"not a temp").unwrap();
+        let mut removed = vec![];
+        remove_stale(&dir, &mut |path| removed.push(path.to_path_buf())).unwrap();
+        assert_eq!(removed, vec![stale]);
+        assert!(dir.join(".cprs-tmp").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,153 @@
+use std::{path::Path, str::FromStr as _};
+
+use regex::Regex;
+use win_tree::BuildMethod;
+
+use cprs::{Engine, Format, Limit, Options, Preserve, Specials, Strategy, Symlinks};
+
+const ARG_DEPTH_KEY: &str = "-d";
+const ARG_EXCLUDE_KEY: &str = "-e";
+const ARG_METHOD_KEY: &str = "-m";
+const ARG_STRATEGY_KEY: &str = "-s";
+// Same as `-s async`, kept for the older invocation.
+const ARG_ASYNC_KEY: &str = "-a";
+const ARG_WORKERS_KEY: &str = "-j";
+const ARG_DIR_WORKERS_KEY: &str = "--dir-workers";
+const ARG_DRY_RUN_KEY: &str = "--dry-run";
+const ARG_JSON_KEY: &str = "--json";
+const ARG_INPLACE_KEY: &str = "--inplace";
+const ARG_PRESERVE_KEY: &str = "--preserve";
+const ARG_SYMLINKS_KEY: &str = "--symlinks";
+const ARG_SPECIALS_KEY: &str = "--specials";
+const ARG_ENGINE_KEY: &str = "--engine";
+const ARG_BWLIMIT_KEY: &str = "--bwlimit";
+const ARG_FILES_PER_SEC_KEY: &str = "--files-per-sec";
+const ARG_RETRIES_KEY: &str = "--retries";
+
+const DEFAULT_RETRIES: u32 = 3;
+
+pub const USAGE: &str = "usage: cprs <source> <dest> [options], see the Readme for the options";
+
+/// Represents everything given on the command line.
+#[derive(Debug)]
+pub struct Config {
+    /// Config for building the tree of source, its path is the source path.
+    pub tree: win_tree::Config,
+    /// Directory the source is copied into, or the archive it is written to.
+    pub dest: String,
+    pub strategy: Strategy,
+    /// Set when the destination is an archive to write, detected from its extension.
+    pub to_archive: Option<Format>,
+    /// Set when the source is an archive file to extract, detected from its extension.
+    pub from_archive: Option<Format>,
+    pub options: Options,
+    /// Only print what would be transferred.
+    pub dry_run: bool,
+    /// Print events and state as JSON lines instead of rendering them.
+    pub json: bool,
+}
+
+/// Parses value of `key` with `FromStr`, errors name the arg it was given for.
+fn parse<T: std::str::FromStr>(key: &str, value: String) -> Result<T, String> {
+    value
+        .parse::<T>()
+        .map_err(|_| format!("invalid value `{value}` for `{key}`"))
+}
+
+/// Builds the config from command line args, an error says what was wrong with them.
+pub fn build_from_args(mut args: impl Iterator<Item = String>) -> Result<Config, String> {
+    // Skip the process name arg.
+    args.next();
+    let mut config = Config {
+        tree: win_tree::Config {
+            path: args.next().ok_or("source path is required")?,
+            depth_check: None,
+            exclude_pattern: None,
+            build_method: BuildMethod::ParallelRayon,
+            follow_symlinks: false,
+        },
+        dest: args.next().ok_or("destination is required")?,
+        strategy: Strategy::default(),
+        to_archive: None,
+        from_archive: None,
+        options: Options {
+            retries: DEFAULT_RETRIES,
+            ..Options::default()
+        },
+        dry_run: false,
+        json: false,
+    };
+    // Applied once the strategy is known, as these can be given before it.
+    let (mut workers, mut dir_workers) = (None, None);
+    while let Some(item) = args.next() {
+        if !item.starts_with('-') {
+            return Err(format!(
+                "unexpected arg `{item}`, each option must start with a hyphen"
+            ));
+        }
+        let mut value = || {
+            args.next()
+                .ok_or_else(|| format!("missing value for `{item}`"))
+        };
+        match item.as_str() {
+            ARG_DEPTH_KEY => config.tree.depth_check = Some(parse(&item, value()?)?),
+            ARG_EXCLUDE_KEY => {
+                let pattern = value()?;
+                Regex::new(&pattern)
+                    .map_err(|_| format!("invalid pattern `{pattern}` for `{item}`"))?;
+                config.tree.exclude_pattern = Some(pattern);
+            }
+            ARG_METHOD_KEY => config.tree.build_method = BuildMethod::from_str(&value()?)?,
+            ARG_STRATEGY_KEY => config.strategy = Strategy::from_str(&value()?)?,
+            ARG_WORKERS_KEY => workers = Some(parse::<usize>(&item, value()?)?),
+            ARG_DIR_WORKERS_KEY => dir_workers = Some(parse::<usize>(&item, value()?)?),
+            ARG_ASYNC_KEY => config.strategy = Strategy::Async,
+            ARG_DRY_RUN_KEY => config.dry_run = true,
+            ARG_JSON_KEY => config.json = true,
+            ARG_INPLACE_KEY => config.options.inplace = true,
+            ARG_PRESERVE_KEY => config.options.preserve = Preserve::from_str(&value()?)?,
+            ARG_SYMLINKS_KEY => config.options.symlinks = Symlinks::from_str(&value()?)?,
+            ARG_SPECIALS_KEY => config.options.specials = Specials::from_str(&value()?)?,
+            ARG_ENGINE_KEY => config.options.engine = Engine::from_str(&value()?)?,
+            ARG_BWLIMIT_KEY => config.options.bytes_per_sec = Limit::from_str(&value()?)?.0,
+            ARG_FILES_PER_SEC_KEY => config.options.files_per_sec = Limit::from_str(&value()?)?.0,
+            ARG_RETRIES_KEY => config.options.retries = parse(&item, value()?)?,
+            _ => return Err(format!("unknown option `{item}`")),
+        }
+    }
+    if workers == Some(0) || dir_workers == Some(0) {
+        return Err(String::from("worker count must be more than 0"));
+    }
+    match &mut config.strategy {
+        Strategy::Rayon { workers: w } => {
+            *w = workers.or(*w);
+        }
+        Strategy::Threadpool {
+            workers: w,
+            dir_workers: d,
+        } => {
+            *w = workers.unwrap_or(*w);
+            *d = dir_workers.unwrap_or(*d);
+        }
+        Strategy::Serial | Strategy::Async if workers.is_some() => {
+            return Err(format!(
+                "`{ARG_WORKERS_KEY}` is only supported by rayon and threadpool strategies"
+            ));
+        }
+        Strategy::Serial | Strategy::Async => {}
+    }
+    config.tree.follow_symlinks = config.options.symlinks == Symlinks::Follow;
+    // Directories named like archives are still directories.
+    if !Path::new(&config.dest).is_dir() {
+        config.to_archive = Format::detect(&config.dest);
+    }
+    if Path::new(&config.tree.path).is_file() {
+        config.from_archive = Format::detect(&config.tree.path);
+    }
+    if config.to_archive.is_some() && config.from_archive.is_some() {
+        return Err(String::from(
+            "copying from an archive into another one is not supported",
+        ));
+    }
+    Ok(config)
+}
@@ -0,0 +1,61 @@
+//! Controls accepted by a running transfer.
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Condvar, Mutex,
+};
+
+/// Represents control signals accepted by a running `Copier`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Control {
+    /// Changes the limit of bytes per second, `None` removes it.
+    BytesPerSec(Option<u64>),
+    /// Changes the limit of files per second, `None` removes it.
+    FilesPerSec(Option<u64>),
+    /// Workers block before their next chunk or path till resumed.
+    Pause,
+    Resume,
+    /// Paths in flight stop after their current chunk and have their temp files removed, rest of them are skipped.
+    Cancel,
+}
+
+/// Pause and cancel flags shared with the workers, which check them before each path and between chunks.
+#[derive(Debug, Default)]
+pub struct Switch {
+    paused: Mutex<bool>,
+    resumed: Condvar,
+    cancelled: AtomicBool,
+}
+
+impl Switch {
+    pub fn pause(&self) {
+        *self.paused.lock().unwrap() = true;
+    }
+
+    pub fn resume(&self) {
+        *self.paused.lock().unwrap() = false;
+        self.resumed.notify_all();
+    }
+
+    /// Cancelling also wakes up paused workers so that they can skip the rest.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+        self.resume();
+    }
+
+    /// Blocks while paused, returns whether the worker should carry on.
+    pub fn wait(&self) -> bool {
+        let mut paused = self.paused.lock().unwrap();
+        while *paused {
+            paused = self.resumed.wait(paused).unwrap();
+        }
+        !self.is_cancelled()
+    }
+
+    pub fn is_paused(&self) -> bool {
+        *self.paused.lock().unwrap()
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+}
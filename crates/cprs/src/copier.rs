@@ -0,0 +1,1640 @@
+//! Transfer of a tree from source to destination.
+use std::{
+    fmt, fs,
+    io::{self, Write},
+    os::unix::fs::PermissionsExt,
+    path::{Component, Path},
+    str::FromStr,
+    sync::{
+        mpsc::{channel, Receiver, Sender},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
+    time::Instant,
+};
+
+use futures::executor::block_on;
+use rayon::prelude::*;
+use serde::Serialize;
+
+use crate::{
+    archive, atomic,
+    control::{Control, Switch},
+    delta, engine,
+    event::{Event, EventType, State, Update},
+    metadata, retry, special, throttle, ui,
+};
+
+/// File jobs of threadpool strategy which can be queued per worker, directory jobs wait for space beyond that so
+/// that a huge tree is not turned into closures all at once.
+const QUEUED_FILES_PER_WORKER: usize = 64;
+
+/// Files of threadpool strategy from this size on are queued at low priority, so that smaller ones go ahead of them.
+const LOW_PRIORITY_FILE_BYTES: u64 = 1 << 26;
+
+/// Represents options controlling how each path is transferred.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Options {
+    /// Patch existing destination files in place instead of through a temp file.
+    pub inplace: bool,
+    /// Metadata to carry over from source paths.
+    pub preserve: metadata::Preserve,
+    pub symlinks: special::Symlinks,
+    pub specials: special::Specials,
+    /// Engine used to copy bytes of files, forced for every file unless it is `Auto`.
+    pub engine: engine::Engine,
+    /// Initial limits, these can be changed through `Control` while copying.
+    pub bytes_per_sec: Option<u64>,
+    pub files_per_sec: Option<u64>,
+    /// Times a path is retried after a transient error.
+    pub retries: u32,
+}
+
+/// Represents how the paths of the tree are transferred.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Strategy {
+    /// One path after another on a single thread.
+    Serial,
+    /// Recursive async transfer run by a `block_on` future executor.
+    Async,
+    /// Children of each directory are transferred in parallel with rayon's `par_iter`, in a pool of `workers` threads
+    /// or in the global one if not given.
+    Rayon { workers: Option<usize> },
+    /// Every non directory path is transferred as a job of the workspace `threadpool` with `workers` threads.
+    /// Directories are created by a separate pool of `dir_workers` threads which hands their children over.
+    Threadpool { workers: usize, dir_workers: usize },
+}
+
+impl Default for Strategy {
+    fn default() -> Self {
+        Strategy::Rayon { workers: None }
+    }
+}
+
+impl FromStr for Strategy {
+    fn from_str(strategy: &str) -> Result<Self, Self::Err> {
+        match strategy {
+            "serial" => Ok(Self::Serial),
+            "async" => Ok(Self::Async),
+            "rayon" => Ok(Self::default()),
+            "threadpool" => Ok(Self::Threadpool {
+                workers: thread::available_parallelism().map_or(4, |n| n.get()),
+                dir_workers: 2,
+            }),
+            _ => Err(String::from("invalid strategy")),
+        }
+    }
+
+    type Err = String;
+}
+
+/// Represents what a transfer would do to one path, this is what a dry run prints.
+#[derive(Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    CreateDir,
+    Copy,
+    /// Destination already has a file at this path which would be patched.
+    Delta,
+    Symlink,
+    Special,
+    SkipSpecial,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PlanEntry {
+    pub action: Action,
+    pub source: String,
+    pub dest: String,
+    pub bytes: u64,
+}
+
+impl fmt::Display for PlanEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let action = match self.action {
+            Action::CreateDir => "mkdir",
+            Action::Copy => "copy",
+            Action::Delta => "delta",
+            Action::Symlink => "symlink",
+            Action::Special => "special",
+            Action::SkipSpecial => "skip",
+        };
+        write!(
+            f,
+            "{:<8} {} -> {} ({} bytes)",
+            action, self.source, self.dest, self.bytes
+        )
+    }
+}
+
+/// Represents everything shared by transfer tasks apart from the paths they work on.
+struct Context {
+    options: Options,
+    event_sender: Sender<Event>,
+    hard_links: special::HardLinks,
+    throttle: Arc<throttle::Throttle>,
+    switch: Arc<Switch>,
+}
+
+impl Context {
+    fn send(&self, path: &str, event_type: EventType) {
+        self.event_sender
+            .send(Event {
+                path: String::from(path),
+                event_type,
+            })
+            .unwrap();
+    }
+
+    fn throttle_bytes(&self, path: &str, count: u64) {
+        let waited = self.throttle.bytes(count);
+        if !waited.is_zero() {
+            self.send(path, EventType::Throttled(waited.as_micros() as u64));
+        }
+    }
+
+    /// Called by workers between chunks, blocks while the transfer is paused and errors out once it is cancelled.
+    fn checkpoint(&self) -> io::Result<()> {
+        if self.switch.wait() {
+            return Ok(());
+        }
+        Err(io::Error::other("transfer cancelled"))
+    }
+
+    /// Runs `transfer` for `path`, retrying transient errors with a backoff. Bytes reported by a failed attempt are
+    /// reverted and the last error is reported as `Failed`, or as `Cancelled` if the transfer was cancelled meanwhile.
+    /// Returns whether the path was transferred.
+    fn attempt(
+        &self,
+        path: &str,
+        mut transfer: impl FnMut(&mut Reported) -> io::Result<()>,
+    ) -> bool {
+        let mut retry = 0;
+        loop {
+            let mut reported = Reported::default();
+            let Err(e) = transfer(&mut reported) else {
+                return true;
+            };
+            if reported.copied > 0 || reported.matched > 0 || reported.holes > 0 {
+                self.send(
+                    path,
+                    EventType::Reverted(reported.copied, reported.matched, reported.holes),
+                );
+            }
+            if self.switch.is_cancelled() {
+                self.send(path, EventType::Cancelled);
+                return false;
+            }
+            if retry >= self.options.retries || !retry::is_transient(&e) {
+                self.send(path, EventType::Failed(e.to_string()));
+                return false;
+            }
+            retry += 1;
+            self.send(path, EventType::Retrying(retry, e.to_string()));
+            thread::sleep(retry::backoff(retry));
+        }
+    }
+
+    fn throttle_file(&self, path: &str) {
+        let waited = self.throttle.file();
+        if !waited.is_zero() {
+            self.send(path, EventType::Throttled(waited.as_micros() as u64));
+        }
+    }
+}
+
+/// Bytes reported by one attempt of transferring a path.
+#[derive(Debug, Default)]
+struct Reported {
+    copied: u64,
+    matched: u64,
+    holes: u64,
+}
+
+/// Transfer of a tree which is planned but not started yet, the tree is walked when this is built so that the totals
+/// are known upfront.
+pub struct Copier {
+    /// Root of the tree, or the archive being extracted.
+    source: String,
+    /// Directory the tree is copied or extracted into, or the archive it is written to.
+    dest: String,
+    mode: Mode,
+    options: Options,
+    tree_root: Arc<win_tree::TreeNode>,
+    state: Arc<State>,
+    throttle: Arc<throttle::Throttle>,
+    switch: Arc<Switch>,
+}
+
+/// Represents where paths are read from and written to.
+#[derive(Debug, Clone, Copy)]
+enum Mode {
+    /// Tree is copied into a directory with given strategy.
+    Copy(Strategy),
+    /// Tree is written into an archive.
+    Archive(archive::Format),
+    /// Entries of an archive are extracted into a directory.
+    Extract(archive::Format),
+}
+
+/// Transfer started by `Copier::start`. Its events are received through `events`, which is closed once the transfer is
+/// done. Dropping the handle waits for the transfer.
+pub struct Handle {
+    state: Arc<State>,
+    switch: Arc<Switch>,
+    control_sender: Sender<Control>,
+    event_receiver: Receiver<Update>,
+    copier_handle: Option<JoinHandle<()>>,
+    event_handle: Option<JoinHandle<()>>,
+}
+
+impl Copier {
+    /// Builds the tree of `tree_config.path` which is to be copied into `dest_dir`.
+    pub fn new(
+        mut tree_config: win_tree::Config,
+        dest_dir: String,
+        strategy: Strategy,
+        options: Options,
+    ) -> io::Result<Self> {
+        let (source_path, _dest_path) = Self::init_path(&tree_config.path, &dest_dir)?;
+        let source = Self::path_string(source_path)?;
+        tree_config.path = source.clone();
+        let tree_root = win_tree::build(tree_config)?;
+        Ok(Self::plan_tree(
+            source,
+            dest_dir,
+            Mode::Copy(strategy),
+            tree_root,
+            options,
+        ))
+    }
+
+    /// Builds the tree of `tree_config.path` which is to be written into a new archive at `archive_path`.
+    pub fn to_archive(
+        mut tree_config: win_tree::Config,
+        archive_path: String,
+        format: archive::Format,
+        options: Options,
+    ) -> io::Result<Self> {
+        let source = Self::path_string(Path::new(&tree_config.path).canonicalize()?)?;
+        tree_config.path = source.clone();
+        if Path::new(&archive_path).is_dir() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "archive path is a directory",
+            ));
+        }
+        let tree_root = win_tree::build(tree_config)?;
+        Ok(Self::plan_tree(
+            source,
+            archive_path,
+            Mode::Archive(format),
+            tree_root,
+            options,
+        ))
+    }
+
+    /// Lists the archive at `archive_path` whose entries are to be extracted into `dest_dir`, same as `tar -C`.
+    pub fn extract(
+        archive_path: String,
+        format: archive::Format,
+        dest_dir: String,
+        options: Options,
+    ) -> io::Result<Self> {
+        let tree_root = archive::tree(Path::new(&archive_path), format)?;
+        Ok(Self::plan_tree(
+            archive_path,
+            dest_dir,
+            Mode::Extract(format),
+            tree_root,
+            options,
+        ))
+    }
+
+    fn plan_tree(
+        source: String,
+        dest: String,
+        mode: Mode,
+        tree_root: win_tree::TreeNode,
+        options: Options,
+    ) -> Self {
+        let tree_root = Arc::new(tree_root);
+        let switch = Arc::new(Switch::default());
+        let throttle = Arc::new(throttle::Throttle::new(
+            options.bytes_per_sec,
+            options.files_per_sec,
+        ));
+        fn get_counts(node: Arc<win_tree::TreeNode>) -> (u64, u64, u64, u64) {
+            // Symlinks and special files are counted as files as each of them completes like one.
+            if node.node_type != win_tree::NodeType::Directory {
+                return (1, 1, 0, node.size_in_bytes.unwrap_or(0));
+            }
+            // Sizes are summed here instead of taking the one of root, which is not known when depth is limited.
+            let (mut total, mut files, mut folders, mut bytes) = (1, 0, 1, 0);
+            for c in &node.children {
+                let (c_total, c_files, c_folders, c_bytes) = get_counts(c.clone());
+                total += c_total;
+                files += c_files;
+                folders += c_folders;
+                bytes += c_bytes;
+            }
+            (total, files, folders, bytes)
+        }
+        let (total, files, folders, bytes) = get_counts(tree_root.clone());
+        let state = State {
+            total_count: total,
+            file_count: files,
+            folder_count: folders,
+            total_bytes: bytes,
+            copied_file_count: 0.into(),
+            copied_bytes: 0.into(),
+            matched_bytes: 0.into(),
+            hole_bytes: 0.into(),
+            linked_bytes: 0.into(),
+            cancelled_count: 0.into(),
+            throttled_micros: 0.into(),
+            throttle: throttle.clone(),
+            switch: switch.clone(),
+            failures: Mutex::new(vec![]),
+            started_at: Instant::now(),
+            throughput: Mutex::new(ui::Throughput::new()),
+            files: Mutex::new(ui::Files::default()),
+        };
+        Copier {
+            source,
+            dest,
+            mode,
+            options,
+            tree_root,
+            state: Arc::new(state),
+            throttle,
+            switch,
+        }
+    }
+
+    pub fn state(&self) -> Arc<State> {
+        self.state.clone()
+    }
+
+    /// Starts the transfer in background threads.
+    pub fn start(self) -> Handle {
+        let Copier {
+            source,
+            dest,
+            mode,
+            mut options,
+            tree_root,
+            state,
+            throttle,
+            switch,
+        } = self;
+        // Archives are single streams which can not be rewound to retry a path.
+        if !matches!(mode, Mode::Copy(_)) {
+            options.retries = 0;
+        }
+        let (event_sender, event_receiver) = channel::<Update>();
+        let (internal_event_tx, internal_event_rx) = channel::<Event>();
+        let context = Arc::new(Context {
+            options,
+            event_sender: internal_event_tx,
+            hard_links: special::HardLinks::default(),
+            throttle: throttle.clone(),
+            switch: switch.clone(),
+        });
+        let (control_sender, control_receiver) = channel::<Control>();
+        let control_switch = switch.clone();
+        // Runs till every sender of controls is dropped, which may be after the transfer is done.
+        thread::spawn(move || {
+            while let Ok(control) = control_receiver.recv() {
+                match control {
+                    Control::BytesPerSec(rate) => throttle.set_bytes_per_sec(rate),
+                    Control::FilesPerSec(rate) => throttle.set_files_per_sec(rate),
+                    Control::Pause => control_switch.pause(),
+                    Control::Resume => control_switch.resume(),
+                    Control::Cancel => control_switch.cancel(),
+                }
+            }
+        });
+        let event_state = state.clone();
+        let event_handle = Some(thread::spawn(move || {
+            let state = event_state;
+            while let Ok(event) = internal_event_rx.recv() {
+                match event.event_type {
+                    EventType::DataCopied(bytes_copied) => {
+                        state
+                            .copied_bytes
+                            .fetch_add(bytes_copied, std::sync::atomic::Ordering::Relaxed);
+                        state
+                            .files
+                            .lock()
+                            .unwrap()
+                            .progress(&event.path, bytes_copied);
+                    }
+                    EventType::DataMatched(bytes_matched) => {
+                        state
+                            .matched_bytes
+                            .fetch_add(bytes_matched, std::sync::atomic::Ordering::Relaxed);
+                        state
+                            .files
+                            .lock()
+                            .unwrap()
+                            .progress(&event.path, bytes_matched);
+                    }
+                    EventType::HoleSkipped(bytes_skipped) => {
+                        state
+                            .hole_bytes
+                            .fetch_add(bytes_skipped, std::sync::atomic::Ordering::Relaxed);
+                        state
+                            .files
+                            .lock()
+                            .unwrap()
+                            .progress(&event.path, bytes_skipped);
+                    }
+                    EventType::PathStarted(size) => {
+                        state.files.lock().unwrap().start(&event.path, size);
+                    }
+                    EventType::HardLinked(_, bytes_linked) => {
+                        state
+                            .linked_bytes
+                            .fetch_add(bytes_linked, std::sync::atomic::Ordering::Relaxed);
+                    }
+                    EventType::PathCompleted => {
+                        state
+                            .copied_file_count
+                            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                        state.files.lock().unwrap().finish(&event.path, true);
+                    }
+                    EventType::Throttled(micros) => {
+                        state
+                            .throttled_micros
+                            .fetch_add(micros, std::sync::atomic::Ordering::Relaxed);
+                    }
+                    EventType::Reverted(bytes_copied, bytes_matched, bytes_skipped) => {
+                        state
+                            .copied_bytes
+                            .fetch_sub(bytes_copied, std::sync::atomic::Ordering::Relaxed);
+                        state
+                            .matched_bytes
+                            .fetch_sub(bytes_matched, std::sync::atomic::Ordering::Relaxed);
+                        state
+                            .hole_bytes
+                            .fetch_sub(bytes_skipped, std::sync::atomic::Ordering::Relaxed);
+                        state
+                            .files
+                            .lock()
+                            .unwrap()
+                            .revert(&event.path, bytes_copied + bytes_matched + bytes_skipped);
+                    }
+                    EventType::Cancelled => {
+                        state
+                            .cancelled_count
+                            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                        state.files.lock().unwrap().finish(&event.path, false);
+                    }
+                    EventType::Failed(ref reason) => {
+                        state
+                            .failures
+                            .lock()
+                            .unwrap()
+                            .push((event.path.clone(), reason.clone()));
+                        state.files.lock().unwrap().finish(&event.path, false);
+                    }
+                    EventType::SymlinkCreated(_)
+                    | EventType::SpecialCreated
+                    | EventType::SpecialSkipped
+                    | EventType::Retrying(_, _)
+                    | EventType::TempRemoved => {}
+                }
+                state.sample_throughput();
+                // Receiver may be dropped by callers which are not interested in events.
+                let _ = event_sender.send((event, state.clone()));
+            }
+        }));
+        let copier_handle = Some(thread::spawn(move || {
+            let dest_path = match mode {
+                Mode::Copy(_) => format!("{}/{}", dest, tree_root.name),
+                Mode::Archive(_) | Mode::Extract(_) => dest.clone(),
+            };
+            let is_dir = !matches!(mode, Mode::Archive(_))
+                && tree_root.node_type == win_tree::NodeType::Directory;
+            let listed = matches!(mode, Mode::Extract(_)).then_some(&*tree_root);
+            Self::remove_stale_temps(&dest_path, is_dir, listed, &context);
+            match mode {
+                Mode::Copy(strategy) => {
+                    Self::run(source, dest, tree_root.clone(), context.clone(), strategy)
+                }
+                Mode::Archive(format) => {
+                    Self::write_archive(&source, &dest, &tree_root, &context, format)
+                }
+                Mode::Extract(format) => Self::extract_archive(&source, &dest, &context, format),
+            }
+            // Temp files of the paths cut short are removed by those paths, anything missed is swept here so that a
+            // cancelled transfer leaves only complete files behind.
+            if context.switch.is_cancelled() {
+                Self::remove_stale_temps(&dest_path, is_dir, listed, &context);
+            }
+        }));
+        Handle {
+            state,
+            switch,
+            control_sender,
+            event_receiver,
+            copier_handle,
+            event_handle,
+        }
+    }
+
+    /// Files of an earlier run which was interrupted while writing them are left as temp files, those are removed
+    /// before anything else is written. An archive is extracted into a directory which may be written to by others, so
+    /// only the temp files of the paths `listed` in the archive are removed there.
+    fn remove_stale_temps(
+        dest_path: &str,
+        is_dir: bool,
+        listed: Option<&win_tree::TreeNode>,
+        context: &Context,
+    ) {
+        let mut on_removed =
+            |temp: &Path| context.send(&temp.to_string_lossy(), EventType::TempRemoved);
+        if let Some(listed) = listed {
+            Self::remove_listed_temps(Path::new(dest_path), listed, &mut on_removed);
+            return;
+        }
+        if !is_dir {
+            let temp = atomic::temp_path(Path::new(dest_path));
+            if fs::remove_file(&temp).is_ok() {
+                on_removed(&temp);
+            }
+            return;
+        }
+        if !Path::new(dest_path).is_dir() {
+            return;
+        }
+        context.attempt(dest_path, |_| {
+            atomic::remove_stale(Path::new(dest_path), &mut on_removed)
+        });
+    }
+
+    fn remove_listed_temps(
+        dir: &Path,
+        listed: &win_tree::TreeNode,
+        on_removed: &mut dyn FnMut(&Path),
+    ) {
+        for child in &listed.children {
+            // Escaping entries are listed under their whole name, which is not a path inside of `dir`.
+            let mut components = Path::new(&child.name).components();
+            let (Some(Component::Normal(_)), None) = (components.next(), components.next()) else {
+                continue;
+            };
+            let path = dir.join(&child.name);
+            if child.node_type != win_tree::NodeType::Directory {
+                let temp = atomic::temp_path(&path);
+                if fs::remove_file(&temp).is_ok() {
+                    on_removed(&temp);
+                }
+            } else if fs::symlink_metadata(&path).is_ok_and(|metadata| metadata.is_dir()) {
+                Self::remove_listed_temps(&path, child, on_removed);
+            }
+        }
+    }
+
+    fn run(
+        source: String,
+        dest_dir: String,
+        tree_root: Arc<win_tree::TreeNode>,
+        context: Arc<Context>,
+        strategy: Strategy,
+    ) {
+        match strategy {
+            Strategy::Async => block_on(Self::transfer_async(
+                source,
+                dest_dir,
+                tree_root.clone(),
+                context,
+            )),
+            Strategy::Serial => Self::transfer(source, dest_dir, tree_root, context, false),
+            Strategy::Rayon { workers: None } => {
+                Self::transfer(source, dest_dir, tree_root, context, true)
+            }
+            Strategy::Rayon {
+                workers: Some(workers),
+            } => rayon::ThreadPoolBuilder::new()
+                .num_threads(workers)
+                .build()
+                .expect("unable to build rayon pool")
+                .install(|| Self::transfer(source, dest_dir, tree_root, context, true)),
+            Strategy::Threadpool {
+                workers,
+                dir_workers,
+            } => Self::transfer_pool(source, dest_dir, tree_root, context, workers, dir_workers),
+        }
+    }
+
+    /// Lists what `start` would do for every path without touching the destination.
+    pub fn plan(&self) -> Vec<PlanEntry> {
+        fn walk(
+            source: String,
+            dest: String,
+            node: &win_tree::TreeNode,
+            copier: &Copier,
+            plan: &mut Vec<PlanEntry>,
+        ) {
+            let copying = matches!(copier.mode, Mode::Copy(_));
+            let action = match node.node_type {
+                win_tree::NodeType::Directory => Action::CreateDir,
+                win_tree::NodeType::Symlink => Action::Symlink,
+                // Archives are written and extracted with only files, directories and symlinks.
+                win_tree::NodeType::Special
+                    if !copying || copier.options.specials == special::Specials::Skip =>
+                {
+                    Action::SkipSpecial
+                }
+                win_tree::NodeType::Special => Action::Special,
+                win_tree::NodeType::File if copying && Path::new(&dest).is_file() => Action::Delta,
+                win_tree::NodeType::File => Action::Copy,
+            };
+            plan.push(PlanEntry {
+                action,
+                source: source.clone(),
+                dest: dest.clone(),
+                bytes: node.size_in_bytes.unwrap_or(0),
+            });
+            for child in &node.children {
+                walk(
+                    format!("{}/{}", source, child.name),
+                    format!("{}/{}", dest, child.name),
+                    child,
+                    copier,
+                    plan,
+                );
+            }
+        }
+        // Archives are listed as directories, the root of an extracted one being the destination directory itself.
+        let dest = match self.mode {
+            Mode::Extract(_) => self.dest.clone(),
+            Mode::Copy(_) | Mode::Archive(_) => format!("{}/{}", self.dest, self.tree_root.name),
+        };
+        let mut plan = vec![];
+        walk(self.source.clone(), dest, &self.tree_root, self, &mut plan);
+        plan
+    }
+
+    fn init_path(
+        source: &String,
+        dest_dir: &String,
+    ) -> io::Result<(std::path::PathBuf, std::path::PathBuf)> {
+        let source_path = Path::new(&source).canonicalize()?;
+        let dest_path =
+            Path::new(&dest_dir)
+                .canonicalize()?
+                .join(source_path.file_name().ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidInput, "source has no name")
+                })?);
+        Ok((source_path, dest_path))
+    }
+
+    fn path_string(path: std::path::PathBuf) -> io::Result<String> {
+        path.into_os_string().into_string().map_err(|path| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("path {path:?} is not valid UTF-8"),
+            )
+        })
+    }
+
+    fn copy(
+        source_path: &String,
+        dest_path: &String,
+        context: &Context,
+        reported: &mut Reported,
+    ) -> io::Result<()> {
+        let source_metadata = fs::symlink_metadata(source_path)?;
+        let claim = match context.hard_links.register(&source_metadata) {
+            special::Link::Original(claim) => claim,
+            special::Link::To(original) => {
+                special::hard_link(&original, Path::new(dest_path))?;
+                context.send(
+                    source_path,
+                    EventType::HardLinked(
+                        original.to_string_lossy().to_string(),
+                        source_metadata.len(),
+                    ),
+                );
+                context.send(source_path, EventType::PathCompleted);
+                return Ok(());
+            }
+        };
+        if Path::new(dest_path).is_file() {
+            Self::copy_delta(source_path, dest_path, context, reported)?;
+        } else {
+            Self::copy_data(source_path, dest_path, context, reported)?;
+        }
+        metadata::apply(
+            Path::new(source_path),
+            Path::new(dest_path),
+            &context.options.preserve,
+        )?;
+        claim.complete(Path::new(dest_path));
+        context.send(source_path, EventType::PathCompleted);
+        Ok(())
+    }
+
+    fn copy_data(
+        source_path: &String,
+        dest_path: &String,
+        context: &Context,
+        reported: &mut Reported,
+    ) -> io::Result<()> {
+        let mut source_file = fs::OpenOptions::new().read(true).open(source_path)?;
+        let len = source_file.metadata()?.len();
+        atomic::write(Path::new(dest_path), |dest_file| {
+            engine::copy(
+                &mut source_file,
+                dest_file,
+                len,
+                context.options.engine,
+                |progress| {
+                    match progress {
+                        engine::Progress::Data(bytes) => {
+                            reported.copied += bytes;
+                            context.send(source_path, EventType::DataCopied(bytes));
+                            context.throttle_bytes(source_path, bytes);
+                        }
+                        // Holes are not written, so they do not count against the bandwidth limit.
+                        engine::Progress::Hole(bytes) => {
+                            reported.holes += bytes;
+                            context.send(source_path, EventType::HoleSkipped(bytes));
+                        }
+                    }
+                    context.checkpoint()
+                },
+            )?;
+            Ok(())
+        })
+    }
+
+    /// Destination already has a version of this file, so only the changed blocks are written.
+    fn copy_delta(
+        source_path: &String,
+        dest_path: &String,
+        context: &Context,
+        reported: &mut Reported,
+    ) -> io::Result<()> {
+        delta::patch(
+            Path::new(source_path),
+            Path::new(dest_path),
+            context.options.inplace,
+            |progress| {
+                let (event_type, bytes) = match progress {
+                    delta::Progress::Literal(bytes) => {
+                        reported.copied += bytes;
+                        (EventType::DataCopied(bytes), bytes)
+                    }
+                    delta::Progress::Matched(bytes) => {
+                        reported.matched += bytes;
+                        (EventType::DataMatched(bytes), bytes)
+                    }
+                };
+                context.send(source_path, event_type);
+                context.throttle_bytes(source_path, bytes);
+                context.checkpoint()
+            },
+        )
+    }
+
+    fn copy_symlink(source_path: &String, dest_path: &String, context: &Context) -> io::Result<()> {
+        let target = special::copy_symlink(Path::new(source_path), Path::new(dest_path))?;
+        metadata::apply(
+            Path::new(source_path),
+            Path::new(dest_path),
+            &context.options.preserve,
+        )?;
+        context.send(
+            source_path,
+            EventType::SymlinkCreated(target.to_string_lossy().to_string()),
+        );
+        context.send(source_path, EventType::PathCompleted);
+        Ok(())
+    }
+
+    /// FIFOs, sockets and device nodes are recreated unless asked to skip them. Device nodes can only be created by
+    /// root, so they are skipped when permission is denied.
+    fn copy_special(source_path: &String, dest_path: &String, context: &Context) -> io::Result<()> {
+        let created = context.options.specials == special::Specials::Create
+            && match special::create_special(Path::new(source_path), Path::new(dest_path)) {
+                Ok(()) => true,
+                Err(e) if e.kind() == io::ErrorKind::PermissionDenied => false,
+                Err(e) => return Err(e),
+            };
+        if created {
+            metadata::apply(
+                Path::new(source_path),
+                Path::new(dest_path),
+                &context.options.preserve,
+            )?;
+            context.send(source_path, EventType::SpecialCreated);
+        } else {
+            context.send(source_path, EventType::SpecialSkipped);
+        }
+        context.send(source_path, EventType::PathCompleted);
+        Ok(())
+    }
+
+    fn create_dir(dest_path: &String) -> io::Result<()> {
+        if Path::new(dest_path).is_dir() {
+            return Ok(());
+        }
+        fs::create_dir(dest_path)
+    }
+
+    /// Creates the directory for `source`, children are not transferred if this returns false.
+    fn transfer_dir(source: &str, dest_path: &String, context: &Context) -> bool {
+        context.switch.wait() && context.attempt(source, |_| Self::create_dir(dest_path))
+    }
+
+    /// Applied after children are written as writing them updates the directory times.
+    fn complete_dir(source: &String, dest_path: &String, context: &Context) {
+        context.attempt(source, |_| {
+            metadata::apply(
+                Path::new(source),
+                Path::new(dest_path),
+                &context.options.preserve,
+            )
+        });
+    }
+
+    /// Transfers a path which is not a directory.
+    fn transfer_leaf(
+        source: &String,
+        dest_path: &String,
+        tree_node: &win_tree::TreeNode,
+        context: &Context,
+    ) {
+        if !context.switch.wait() {
+            return;
+        }
+        context.throttle_file(source);
+        context.send(
+            source,
+            EventType::PathStarted(tree_node.size_in_bytes.unwrap_or(0)),
+        );
+        context.attempt(source, |reported| match tree_node.node_type {
+            win_tree::NodeType::Symlink => Self::copy_symlink(source, dest_path, context),
+            win_tree::NodeType::Special => Self::copy_special(source, dest_path, context),
+            _ => Self::copy(source, dest_path, context, reported),
+        });
+    }
+
+    async fn transfer_async(
+        source: String,
+        dest_dir: String,
+        tree_node: Arc<win_tree::TreeNode>,
+        context: Arc<Context>,
+    ) {
+        let dest_path = format!("{}/{}", dest_dir, tree_node.name);
+        if tree_node.node_type == win_tree::NodeType::Directory {
+            if !Self::transfer_dir(&source, &dest_path, &context) {
+                return;
+            }
+            for child in tree_node.children.iter() {
+                Box::pin(Self::transfer_async(
+                    format!("{}/{}", source, child.name),
+                    dest_path.to_string(),
+                    child.clone(),
+                    context.clone(),
+                ))
+                .await;
+            }
+            Self::complete_dir(&source, &dest_path, &context);
+            return;
+        }
+        Self::transfer_leaf(&source, &dest_path, &tree_node, &context);
+    }
+
+    /// Transfers the tree in the calling thread, children of each directory are transferred in parallel with rayon if
+    /// `parallel` is set.
+    fn transfer(
+        source: String,
+        dest_dir: String,
+        tree_node: Arc<win_tree::TreeNode>,
+        context: Arc<Context>,
+        parallel: bool,
+    ) {
+        let dest_path = format!("{}/{}", dest_dir, tree_node.name);
+        if tree_node.node_type == win_tree::NodeType::Directory {
+            if !Self::transfer_dir(&source, &dest_path, &context) {
+                return;
+            }
+            let transfer_child = |child: &Arc<win_tree::TreeNode>| {
+                let child_source = format!("{}/{}", source, child.name);
+                let child_dest_dir = dest_path.to_string();
+                let child_node = child.clone();
+                let child_context = context.clone();
+                Self::transfer(
+                    child_source,
+                    child_dest_dir,
+                    child_node,
+                    child_context,
+                    parallel,
+                );
+            };
+            if parallel {
+                tree_node.children.par_iter().for_each(transfer_child);
+            } else {
+                tree_node.children.iter().for_each(transfer_child);
+            }
+            Self::complete_dir(&source, &dest_path, &context);
+            return;
+        }
+        Self::transfer_leaf(&source, &dest_path, &tree_node, &context);
+    }
+
+    /// Transfers the tree with two threadpools - directories are created by jobs of the small `dir_workers` pool, each
+    /// of which submits its subdirectories back to the same pool and every other child as a job of the `workers` pool.
+    fn transfer_pool(
+        source: String,
+        dest_dir: String,
+        tree_node: Arc<win_tree::TreeNode>,
+        context: Arc<Context>,
+        workers: usize,
+        dir_workers: usize,
+    ) {
+        fn submit_dir(
+            source: String,
+            dest_dir: &str,
+            tree_node: Arc<win_tree::TreeNode>,
+            context: Arc<Context>,
+            senders: Arc<PoolSenders>,
+        ) {
+            let dest_path = format!("{}/{}", dest_dir, tree_node.name);
+            let dir_senders = senders.clone();
+            let added = dir_senders.dirs.add(Box::new(move || {
+                if !Copier::transfer_dir(&source, &dest_path, &context) {
+                    return None;
+                }
+                for child in &tree_node.children {
+                    let child_source = format!("{}/{}", source, child.name);
+                    if child.node_type == win_tree::NodeType::Directory {
+                        submit_dir(
+                            child_source,
+                            &dest_path,
+                            child.clone(),
+                            context.clone(),
+                            senders.clone(),
+                        );
+                        continue;
+                    }
+                    let (child_dest, child, context) = (
+                        format!("{}/{}", dest_path, child.name),
+                        child.clone(),
+                        context.clone(),
+                    );
+                    let priority = match child.size_in_bytes {
+                        Some(size) if size >= LOW_PRIORITY_FILE_BYTES => threadpool::Priority::Low,
+                        _ => threadpool::Priority::Normal,
+                    };
+                    let added = senders.files.add_with_priority(
+                        priority,
+                        Box::new(move || {
+                            Copier::transfer_leaf(&child_source, &child_dest, &child, &context)
+                        }),
+                    );
+                    added.expect("pools are shut down only after their jobs are done");
+                }
+                Some((source, dest_path))
+            }));
+            added.expect("pools are shut down only after their jobs are done");
+        }
+        let file_pool = threadpool::ThreadPool::with_config(threadpool::Config {
+            core_threads: workers,
+            max_threads: workers,
+            max_queued: Some(workers * QUEUED_FILES_PER_WORKER),
+            when_full: threadpool::WhenFull::Block,
+            ..threadpool::Config::default()
+        });
+        let (file_sender, file_results) = file_pool.sender::<()>();
+        let (dir_pool, dir_sender, dir_results) =
+            threadpool::ThreadPool::new::<Option<(String, String)>>(dir_workers);
+        let senders = Arc::new(PoolSenders {
+            files: file_sender,
+            dirs: dir_sender,
+        });
+        if tree_node.node_type == win_tree::NodeType::Directory {
+            submit_dir(source, &dest_dir, tree_node, context.clone(), senders);
+        } else {
+            let dest_path = format!("{}/{}", dest_dir, tree_node.name);
+            Self::transfer_leaf(&source, &dest_path, &tree_node, &context);
+            drop(senders);
+        }
+        // Every directory job holds the senders till it is done, so results of both pools are closed once the last
+        // job is done. Directory results are waited first as those jobs are the ones adding file jobs.
+        let mut dirs = dir_results
+            .iter()
+            .flatten()
+            .collect::<Vec<(String, String)>>();
+        file_results.iter().for_each(drop);
+        dir_pool.shutdown();
+        file_pool.shutdown();
+        // Deepest directories first, same as the order other strategies complete them in.
+        dirs.sort_by_key(|(_, dest_path)| std::cmp::Reverse(dest_path.matches('/').count()));
+        for (source, dest_path) in dirs {
+            Self::complete_dir(&source, &dest_path, &context);
+        }
+    }
+
+    /// Writes the tree into a new archive. The archive is one path which is written through a temp file, so it is
+    /// either complete or not there at all, while progress is reported for every path in the tree.
+    fn write_archive(
+        source: &str,
+        archive_path: &str,
+        tree_root: &win_tree::TreeNode,
+        context: &Context,
+        format: archive::Format,
+    ) {
+        context.attempt(archive_path, |reported| {
+            atomic::write(Path::new(archive_path), |file| {
+                let mut writer = archive::Writer::new(file, format)?;
+                Self::archive_node(
+                    &mut writer,
+                    source,
+                    &tree_root.name,
+                    tree_root,
+                    context,
+                    reported,
+                )?;
+                writer.finish()
+            })
+        });
+    }
+
+    /// Adds `node` at `name` in the archive. Paths which can not be read are reported as failed and left out, only
+    /// errors of the archive itself are returned.
+    fn archive_node(
+        writer: &mut archive::Writer,
+        source: &str,
+        name: &str,
+        node: &win_tree::TreeNode,
+        context: &Context,
+        reported: &mut Reported,
+    ) -> io::Result<()> {
+        context.checkpoint()?;
+        let metadata = if context.options.symlinks == special::Symlinks::Follow {
+            fs::metadata(source)
+        } else {
+            fs::symlink_metadata(source)
+        };
+        let metadata = match metadata {
+            Ok(metadata) => metadata,
+            Err(e) => {
+                context.send(source, EventType::Failed(e.to_string()));
+                return Ok(());
+            }
+        };
+        match node.node_type {
+            win_tree::NodeType::Directory => {
+                writer.add_dir(name, &metadata)?;
+                for child in &node.children {
+                    Self::archive_node(
+                        writer,
+                        &format!("{}/{}", source, child.name),
+                        &format!("{}/{}", name, child.name),
+                        child,
+                        context,
+                        reported,
+                    )?;
+                }
+                return Ok(());
+            }
+            win_tree::NodeType::File => {
+                context.throttle_file(source);
+                context.send(source, EventType::PathStarted(metadata.len()));
+                // Zip has no hard links, so the data of each link is stored again there.
+                let link = writer
+                    .keeps_hard_links()
+                    .then(|| context.hard_links.register(&metadata));
+                let claim = match link {
+                    Some(special::Link::To(original)) => {
+                        let original = original.to_string_lossy();
+                        writer.add_hard_link(name, &metadata, &original)?;
+                        context.send(
+                            source,
+                            EventType::HardLinked(original.to_string(), metadata.len()),
+                        );
+                        context.send(source, EventType::PathCompleted);
+                        return Ok(());
+                    }
+                    Some(special::Link::Original(claim)) => Some(claim),
+                    None => None,
+                };
+                let file = match fs::File::open(source) {
+                    Ok(file) => file,
+                    Err(e) => {
+                        context.send(source, EventType::Failed(e.to_string()));
+                        return Ok(());
+                    }
+                };
+                let mut data = Reporting::new(file, source, context, reported);
+                writer.add_file(name, &metadata, &mut data)?;
+                data.report()?;
+                if let Some(claim) = claim {
+                    claim.complete(Path::new(name));
+                }
+            }
+            win_tree::NodeType::Symlink => {
+                let target = match fs::read_link(source) {
+                    Ok(target) => target,
+                    Err(e) => {
+                        context.send(source, EventType::Failed(e.to_string()));
+                        return Ok(());
+                    }
+                };
+                writer.add_symlink(name, &metadata, &target)?;
+                context.send(
+                    source,
+                    EventType::SymlinkCreated(target.to_string_lossy().to_string()),
+                );
+            }
+            win_tree::NodeType::Special => context.send(source, EventType::SpecialSkipped),
+        }
+        context.send(source, EventType::PathCompleted);
+        Ok(())
+    }
+
+    /// Extracts entries of the archive into `dest_dir`. Entries which can not be written are reported as failed and
+    /// the rest are carried on with, an archive which can not be read fails as a whole.
+    fn extract_archive(
+        archive_path: &str,
+        dest_dir: &str,
+        context: &Context,
+        format: archive::Format,
+    ) {
+        if !Self::transfer_dir(archive_path, &dest_dir.to_string(), context) {
+            return;
+        }
+        let result = archive::read(Path::new(archive_path), format, &mut |entry| {
+            Self::extract_entry(archive_path, dest_dir, entry, context)
+        });
+        // Reading stops with an error once cancelled, which is not a failure of the archive.
+        if let Err(e) = result {
+            if !context.switch.is_cancelled() {
+                context.send(archive_path, EventType::Failed(e.to_string()));
+            }
+        }
+    }
+
+    fn extract_entry(
+        archive_path: &str,
+        dest_dir: &str,
+        entry: archive::Entry,
+        context: &Context,
+    ) -> io::Result<()> {
+        context.checkpoint()?;
+        let source = format!("{}/{}", archive_path, entry.name.trim_end_matches('/'));
+        let Some(path) = entry.path else {
+            context.send(
+                &source,
+                EventType::Failed(String::from("path is outside of the destination")),
+            );
+            return Ok(());
+        };
+        let dest_dir = Path::new(dest_dir);
+        if entry.node_type == win_tree::NodeType::Directory {
+            context.attempt(&source, |_| {
+                fs::create_dir_all(archive::unpack_path(dest_dir, &path)?)
+            });
+            return Ok(());
+        }
+        if entry.node_type == win_tree::NodeType::File {
+            context.throttle_file(&source);
+            context.send(&source, EventType::PathStarted(entry.size));
+        }
+        let data = entry.data;
+        context.attempt(&source, |reported| {
+            let dest = archive::unpack_path(dest_dir, &path)?;
+            // Archives do not need to have entries for the directories of their paths.
+            if let Some(parent) = dest.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            match (entry.node_type, &entry.link) {
+                (win_tree::NodeType::Symlink, Some(target)) => {
+                    special::create_symlink(target, &dest)?;
+                    context.send(
+                        &source,
+                        EventType::SymlinkCreated(target.to_string_lossy().to_string()),
+                    );
+                }
+                // Hard link of an earlier entry, which has to be a file extracted into the destination as well.
+                (win_tree::NodeType::File, Some(original)) => {
+                    let Some(original) = archive::enclosed(original) else {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidInput,
+                            "hard link is of a path outside of the destination",
+                        ));
+                    };
+                    let original = archive::unpack_path(dest_dir, &original)?;
+                    if !fs::symlink_metadata(&original)?.is_file() {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidInput,
+                            "hard link is not of a file",
+                        ));
+                    }
+                    special::hard_link(&original, &dest)?;
+                    context.send(
+                        &source,
+                        EventType::HardLinked(original.to_string_lossy().to_string(), entry.size),
+                    );
+                }
+                (win_tree::NodeType::File, None) => {
+                    atomic::write(&dest, |file| {
+                        let mut data = Reporting::new(&mut *data, &source, context, reported);
+                        let mut writer = io::BufWriter::new(&mut *file);
+                        io::copy(&mut data, &mut writer)?;
+                        writer.flush()?;
+                        drop(writer);
+                        data.report()?;
+                        if let (true, Some(mode)) = (context.options.preserve.mode, entry.mode) {
+                            file.set_permissions(fs::Permissions::from_mode(mode & 0o7777))?;
+                        }
+                        if let (true, Some(modified)) =
+                            (context.options.preserve.timestamps, entry.modified)
+                        {
+                            file.set_modified(modified)?;
+                        }
+                        Ok(())
+                    })?;
+                }
+                _ => context.send(&source, EventType::SpecialSkipped),
+            }
+            context.send(&source, EventType::PathCompleted);
+            Ok(())
+        });
+        Ok(())
+    }
+}
+
+/// Reader which reports bytes read through it as copied. Bytes are reported in batches so that the small reads of
+/// archive streams do not turn into as many events.
+struct Reporting<'a, R> {
+    inner: R,
+    path: &'a str,
+    context: &'a Context,
+    reported: &'a mut Reported,
+    pending: u64,
+}
+
+impl<'a, R: io::Read> Reporting<'a, R> {
+    const BATCH: u64 = 1 << 18;
+
+    fn new(inner: R, path: &'a str, context: &'a Context, reported: &'a mut Reported) -> Self {
+        Reporting {
+            inner,
+            path,
+            context,
+            reported,
+            pending: 0,
+        }
+    }
+
+    /// Reports bytes read since the last report, this is also the point where the transfer is paused or cancelled.
+    fn report(&mut self) -> io::Result<()> {
+        if self.pending == 0 {
+            return Ok(());
+        }
+        let bytes = std::mem::take(&mut self.pending);
+        self.reported.copied += bytes;
+        self.context.send(self.path, EventType::DataCopied(bytes));
+        self.context.throttle_bytes(self.path, bytes);
+        self.context.checkpoint()
+    }
+}
+
+impl<R: io::Read> io::Read for Reporting<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.pending += n as u64;
+        if self.pending >= Self::BATCH {
+            self.report()?;
+        }
+        Ok(n)
+    }
+}
+
+/// Job senders of the two pools of threadpool strategy, shared by directory jobs to submit the children they find.
+struct PoolSenders {
+    files: threadpool::ThreadPoolJobSender<()>,
+    dirs: threadpool::ThreadPoolJobSender<Option<(String, String)>>,
+}
+
+impl Handle {
+    /// Events of the transfer along with the state after each of them.
+    pub fn events(&self) -> &Receiver<Update> {
+        &self.event_receiver
+    }
+
+    /// Sender of controls which can be handed to other threads, like one reading user input.
+    pub fn controls(&self) -> Sender<Control> {
+        self.control_sender.clone()
+    }
+
+    pub fn state(&self) -> Arc<State> {
+        self.state.clone()
+    }
+
+    pub fn pause(&self) {
+        self.switch.pause();
+    }
+
+    pub fn resume(&self) {
+        self.switch.resume();
+    }
+
+    pub fn cancel(&self) {
+        self.switch.cancel();
+    }
+
+    pub fn set_bytes_per_sec(&self, rate: Option<u64>) {
+        self.state.throttle.set_bytes_per_sec(rate);
+    }
+
+    pub fn set_files_per_sec(&self, rate: Option<u64>) {
+        self.state.throttle.set_files_per_sec(rate);
+    }
+
+    /// Waits for the transfer to be done and returns its final state.
+    pub fn join(mut self) -> Arc<State> {
+        self.wait();
+        self.state.clone()
+    }
+
+    fn wait(&mut self) {
+        if let Some(handle) = self.copier_handle.take() {
+            handle.join().unwrap();
+        }
+        if let Some(handle) = self.event_handle.take() {
+            handle.join().unwrap();
+        }
+    }
+}
+
+impl Drop for Handle {
+    fn drop(&mut self) {
+        self.wait();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn extract(archive_path: &Path, format: archive::Format, dest: &Path) -> Arc<State> {
+        Copier::extract(
+            archive_path.to_string_lossy().to_string(),
+            format,
+            dest.to_string_lossy().to_string(),
+            Options::default(),
+        )
+        .unwrap()
+        .start()
+        .join()
+    }
+
+    #[test]
+    fn extraction_does_not_write_through_symlinks() {
+        let dir = std::env::temp_dir().join(format!("cprs-extract-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let outside = dir.join("outside");
+        fs::create_dir_all(&outside).unwrap();
+
+        // Symlink to a directory outside of the destination, followed by a file inside of it.
+        let tar_path = dir.join("evil.tar");
+        let mut tar = tar::Builder::new(fs::File::create(&tar_path).unwrap());
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Symlink);
+        header.set_size(0);
+        tar.append_link(&mut header, "evil", &outside).unwrap();
+        let mut header = tar::Header::new_gnu();
+        header.set_size(3);
+        header.set_mode(0o644);
+        tar.append_data(&mut header, "evil/pwned", &b"bad"[..])
+            .unwrap();
+        tar.into_inner().unwrap();
+
+        let zip_path = dir.join("evil.zip");
+        let mut zip = zip::ZipWriter::new(fs::File::create(&zip_path).unwrap());
+        let options = zip::write::SimpleFileOptions::default();
+        zip.add_symlink("evil", outside.to_string_lossy(), options)
+            .unwrap();
+        zip.start_file("evil/pwned", options).unwrap();
+        io::Write::write_all(&mut zip, b"bad").unwrap();
+        zip.finish().unwrap();
+
+        for (archive_path, format) in [
+            (tar_path, archive::Format::Tar),
+            (zip_path, archive::Format::Zip),
+        ] {
+            let dest = dir.join("dest");
+            let state = extract(&archive_path, format, &dest);
+            assert!(!outside.join("pwned").exists());
+            let failures = state.failures();
+            assert_eq!(failures.len(), 1);
+            assert!(failures[0].0.ends_with("evil/pwned"));
+            assert!(fs::symlink_metadata(dest.join("evil"))
+                .unwrap()
+                .is_symlink());
+            fs::remove_dir_all(&dest).unwrap();
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn extraction_removes_only_temps_of_its_own_paths() {
+        let dir = std::env::temp_dir().join(format!("cprs-extract-temps-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let dest = dir.join("dest");
+        fs::create_dir_all(dest.join("sub")).unwrap();
+        let tar_path = dir.join("sub.tar");
+        let mut tar = tar::Builder::new(fs::File::create(&tar_path).unwrap());
+        let mut header = tar::Header::new_gnu();
+        header.set_size(3);
+        header.set_mode(0o644);
+        tar.append_data(&mut header, "sub/file", &b"new"[..])
+            .unwrap();
+        tar.into_inner().unwrap();
+        let (own, other) = (
+            atomic::temp_path(&dest.join("sub/file")),
+            atomic::temp_path(&dest.join("sub/other")),
+        );
+        fs::write(&own, b"stale").unwrap();
+        // Temp file of another run writing into the same directory.
+        fs::write(&other, b"in flight").unwrap();
+
+        let state = extract(&tar_path, archive::Format::Tar, &dest);
+        assert!(state.failures().is_empty());
+        assert!(!own.exists());
+        assert!(other.exists());
+        assert_eq!(fs::read(dest.join("sub/file")).unwrap(), b"new");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn hard_links_are_archived_and_extracted_as_links() {
+        use std::os::unix::fs::MetadataExt;
+
+        let dir = std::env::temp_dir().join(format!("cprs-hard-links-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let source = dir.join("src");
+        fs::create_dir_all(&source).unwrap();
+        fs::write(source.join("a"), b"linked").unwrap();
+        fs::hard_link(source.join("a"), source.join("b")).unwrap();
+
+        let archive_path = dir.join("src.tar");
+        let state = Copier::to_archive(
+            win_tree::Config {
+                path: source.to_string_lossy().to_string(),
+                depth_check: None,
+                exclude_pattern: None,
+                build_method: win_tree::BuildMethod::SerialAsync,
+                follow_symlinks: false,
+            },
+            archive_path.to_string_lossy().to_string(),
+            archive::Format::Tar,
+            Options::default(),
+        )
+        .unwrap()
+        .start()
+        .join();
+        assert!(state.failures().is_empty());
+
+        let mut entries = vec![];
+        archive::read(&archive_path, archive::Format::Tar, &mut |entry| {
+            let mut data = vec![];
+            entry.data.read_to_end(&mut data)?;
+            entries.push((entry.name, entry.node_type, entry.link, data));
+            Ok(())
+        })
+        .unwrap();
+        let files = entries
+            .iter()
+            .filter(|(_, node_type, _, _)| *node_type == win_tree::NodeType::File)
+            .collect::<Vec<_>>();
+        // Data is stored only for the first of the links, the other one names it.
+        assert_eq!(files.len(), 2);
+        assert_eq!(files[0].2, None);
+        assert_eq!(files[0].3, b"linked");
+        assert_eq!(files[1].2, Some(std::path::PathBuf::from(&files[0].0)));
+        assert!(files[1].3.is_empty());
+
+        let dest = dir.join("dest");
+        let state = extract(&archive_path, archive::Format::Tar, &dest);
+        assert!(state.failures().is_empty());
+        let (a, b) = (dest.join("src/a"), dest.join("src/b"));
+        assert_eq!(fs::read(&b).unwrap(), b"linked");
+        assert_eq!(
+            fs::metadata(&a).unwrap().ino(),
+            fs::metadata(&b).unwrap().ino()
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn files_changing_size_while_archived_fail() {
+        let dir = std::env::temp_dir().join(format!("cprs-archive-size-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("file");
+        fs::write(&file, b"abc").unwrap();
+        let metadata = fs::metadata(&file).unwrap();
+
+        for format in [archive::Format::Tar, archive::Format::Zip] {
+            let mut archive_file = fs::File::create(dir.join("archive")).unwrap();
+            let mut writer = archive::Writer::new(&mut archive_file, format).unwrap();
+            writer
+                .add_file("same", &metadata, &mut &b"abc"[..])
+                .unwrap();
+            let longer = writer.add_file("longer", &metadata, &mut &b"abcd"[..]);
+            assert_eq!(
+                longer.unwrap_err().to_string(),
+                "file changed size while copying"
+            );
+            let shorter = writer.add_file("shorter", &metadata, &mut &b"ab"[..]);
+            assert_eq!(shorter.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn cancelling_leaves_no_temps_and_skips_unstarted_paths() {
+        let dir = std::env::temp_dir().join(format!("cprs-cancel-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let source = dir.join("src");
+        fs::create_dir_all(&source).unwrap();
+        for i in 0..16 {
+            fs::write(source.join(format!("f{i:02}")), vec![b'x'; 64 << 10]).unwrap();
+        }
+        let dest = dir.join("dest");
+        fs::create_dir_all(&dest).unwrap();
+
+        let handle = Copier::new(
+            win_tree::Config {
+                path: source.to_string_lossy().to_string(),
+                depth_check: None,
+                exclude_pattern: None,
+                build_method: win_tree::BuildMethod::SerialAsync,
+                follow_symlinks: false,
+            },
+            dest.to_string_lossy().to_string(),
+            Strategy::Serial,
+            Options {
+                // Four files a second, so that most of them are not started yet once the first one is done.
+                bytes_per_sec: Some(256 << 10),
+                ..Options::default()
+            },
+        )
+        .unwrap()
+        .start();
+        for (event, _) in handle.events() {
+            if event.event_type == EventType::PathCompleted && event.path.contains("/f") {
+                break;
+            }
+        }
+        handle.cancel();
+        let state = handle.join();
+
+        assert!(state.is_cancelled());
+        let copied = fs::read_dir(dest.join("src"))
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<_>>();
+        assert!(copied.iter().all(|name| !name.ends_with(".cprs-tmp")));
+        assert!(!copied.is_empty() && copied.len() < 16, "{copied:?}");
+        for name in &copied {
+            assert_eq!(
+                fs::metadata(dest.join("src").join(name)).unwrap().len(),
+                64 << 10
+            );
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn relative_sources_are_named_after_their_directory() {
+        let dest = std::env::temp_dir();
+        let copier = Copier::new(
+            win_tree::Config {
+                path: ".".to_string(),
+                depth_check: Some(0),
+                exclude_pattern: None,
+                build_method: win_tree::BuildMethod::SerialAsync,
+                follow_symlinks: false,
+            },
+            dest.to_string_lossy().to_string(),
+            Strategy::Serial,
+            Options::default(),
+        )
+        .unwrap();
+        let cwd = std::env::current_dir().unwrap();
+        let name = cwd.file_name().unwrap().to_str().unwrap();
+        let root = &copier.plan()[0];
+        assert_eq!(root.source, cwd.to_string_lossy());
+        assert_eq!(root.dest, format!("{}/{name}", dest.to_string_lossy()));
+    }
+}
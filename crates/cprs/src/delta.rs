@@ -0,0 +1,361 @@
+//! rsync style delta transfer for files which already exist at the destination.
+//!
+//! - Destination (old) file is split in fixed size blocks and a signature of weak (rolling) and strong checksums is built for each block.
+//! - Source (new) file is scanned with a rolling window of block size, every window whose weak and strong checksums match a destination block is emitted as a `Copy` of that block, everything else is emitted as `Literal` bytes.
+//! - Destination is then patched either through a temp file (blocks can move around) or in place (only blocks at the same offset are reused).
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    fs,
+    hash::Hasher,
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+const MIN_BLOCK_SIZE: usize = 1 << 12;
+const MAX_BLOCK_SIZE: usize = 1 << 17;
+// Pending literal bytes are flushed once they cross this size to keep memory bounded.
+const LITERAL_FLUSH_SIZE: usize = 1 << 16;
+
+/// Block size grows with the square root of file size so that the signature stays small for huge files.
+pub fn block_size_for(len: u64) -> usize {
+    ((len as f64).sqrt() as usize)
+        .next_power_of_two()
+        .clamp(MIN_BLOCK_SIZE, MAX_BLOCK_SIZE)
+}
+
+/// Weak checksum from rsync which can be rolled over by one byte in constant time.
+struct Rolling {
+    a: u32,
+    b: u32,
+    len: u32,
+}
+
+impl Rolling {
+    fn new(window: &[u8]) -> Self {
+        let (mut a, mut b) = (0u32, 0u32);
+        let len = window.len() as u32;
+        for (i, byte) in window.iter().enumerate() {
+            a = a.wrapping_add(*byte as u32);
+            b = b.wrapping_add((len - i as u32).wrapping_mul(*byte as u32));
+        }
+        Rolling { a, b, len }
+    }
+
+    fn roll(&mut self, out: u8, inp: u8) {
+        self.a = self.a.wrapping_sub(out as u32).wrapping_add(inp as u32);
+        self.b = self
+            .b
+            .wrapping_sub(self.len.wrapping_mul(out as u32))
+            .wrapping_add(self.a);
+    }
+
+    fn digest(&self) -> u32 {
+        (self.b << 16) | (self.a & 0xffff)
+    }
+}
+
+fn strong(window: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    hasher.write(window);
+    hasher.finish()
+}
+
+struct Block {
+    strong: u64,
+    len: usize,
+}
+
+/// Checksums of every block of the destination file.
+pub struct Signature {
+    block_size: usize,
+    blocks: Vec<Block>,
+    lookup: HashMap<u32, Vec<usize>>,
+}
+
+impl Signature {
+    pub fn build(mut reader: impl Read, block_size: usize) -> io::Result<Self> {
+        let mut signature = Signature {
+            block_size,
+            blocks: vec![],
+            lookup: HashMap::new(),
+        };
+        let mut buf = vec![0; block_size];
+        loop {
+            let len = read_full(&mut reader, &mut buf)?;
+            if len == 0 {
+                break;
+            }
+            let window = &buf[0..len];
+            signature
+                .lookup
+                .entry(Rolling::new(window).digest())
+                .or_default()
+                .push(signature.blocks.len());
+            signature.blocks.push(Block {
+                strong: strong(window),
+                len,
+            });
+            if len < block_size {
+                break;
+            }
+        }
+        Ok(signature)
+    }
+
+    fn find(&self, weak: u32, window: &[u8], offset: u64, aligned: bool) -> Option<usize> {
+        let candidates = self.lookup.get(&weak)?;
+        let mut window_strong = None;
+        candidates.iter().copied().find(|&index| {
+            let block = &self.blocks[index];
+            if block.len != window.len() || (aligned && (index * self.block_size) as u64 != offset)
+            {
+                return false;
+            }
+            *window_strong.get_or_insert_with(|| strong(window)) == block.strong
+        })
+    }
+}
+
+/// Instruction to rebuild the source from the destination.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Op<'a> {
+    /// Reuse the block with given index from the destination.
+    Copy(usize),
+    /// Bytes not present in the destination.
+    Literal(&'a [u8]),
+}
+
+fn read_full(reader: &mut impl Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+/// Scans the source with a rolling window and emits ops in source order.
+/// With `aligned` set, a block is only reused at its own offset which is what in place patching needs.
+pub fn diff(
+    mut source: impl Read,
+    signature: &Signature,
+    aligned: bool,
+    mut emit: impl FnMut(Op) -> io::Result<()>,
+) -> io::Result<()> {
+    let bs = signature.block_size;
+    let mut data: Vec<u8> = Vec::with_capacity(bs * 2 + LITERAL_FLUSH_SIZE);
+    // `lit` is the start of pending literal bytes and `start` is the start of current window.
+    let (mut lit, mut start, mut offset) = (0, 0, 0u64);
+    let mut eof = false;
+    let mut rolling: Option<Rolling> = None;
+    loop {
+        // Keep one byte more than a window so that it can be rolled.
+        if !eof && data.len() - start <= bs {
+            data.drain(0..lit);
+            start -= lit;
+            lit = 0;
+            let filled = data.len();
+            data.resize(filled + bs + LITERAL_FLUSH_SIZE, 0);
+            let read = read_full(&mut source, &mut data[filled..])?;
+            data.truncate(filled + read);
+            eof = read == 0;
+            continue;
+        }
+        let available = data.len() - start;
+        if available < bs {
+            // Tail shorter than a block can only match the last block of destination.
+            let tail = &data[start..];
+            let matched = if tail.is_empty() {
+                None
+            } else {
+                signature.find(Rolling::new(tail).digest(), tail, offset, aligned)
+            };
+            match matched {
+                Some(index) => {
+                    if lit < start {
+                        emit(Op::Literal(&data[lit..start]))?;
+                    }
+                    emit(Op::Copy(index))?;
+                }
+                None if lit < data.len() => emit(Op::Literal(&data[lit..]))?,
+                None => {}
+            }
+            return Ok(());
+        }
+        let window = &data[start..start + bs];
+        let weak = rolling.get_or_insert_with(|| Rolling::new(window)).digest();
+        if let Some(index) = signature.find(weak, window, offset, aligned) {
+            if lit < start {
+                emit(Op::Literal(&data[lit..start]))?;
+            }
+            emit(Op::Copy(index))?;
+            start += bs;
+            offset += bs as u64;
+            lit = start;
+            rolling = None;
+            continue;
+        }
+        match (rolling.as_mut(), data.get(start + bs)) {
+            (Some(r), Some(inp)) => r.roll(data[start], *inp),
+            _ => rolling = None,
+        }
+        start += 1;
+        offset += 1;
+        if start - lit >= LITERAL_FLUSH_SIZE {
+            emit(Op::Literal(&data[lit..start]))?;
+            lit = start;
+        }
+    }
+}
+
+/// Bytes accounted while patching a file.
+#[derive(Debug)]
+pub enum Progress {
+    Literal(u64),
+    Matched(u64),
+}
+
+/// Patches `dest_path` so that it becomes same as `source_path`.
+///
+/// In place patching writes only changed blocks but can not reuse blocks which moved. Otherwise a temp file is
+/// written next to the destination and renamed over it. `on_progress` is called after every block and an error from it
+/// stops the patching.
+pub fn patch(
+    source_path: &Path,
+    dest_path: &Path,
+    inplace: bool,
+    mut on_progress: impl FnMut(Progress) -> io::Result<()>,
+) -> io::Result<()> {
+    let dest_len = fs::metadata(dest_path)?.len();
+    let block_size = block_size_for(dest_len);
+    let signature = Signature::build(io::BufReader::new(fs::File::open(dest_path)?), block_size)?;
+    let source = io::BufReader::new(fs::File::open(source_path)?);
+    if inplace {
+        let mut dest = fs::OpenOptions::new().write(true).open(dest_path)?;
+        let mut offset = 0u64;
+        diff(source, &signature, true, |op| {
+            match op {
+                Op::Copy(index) => {
+                    let len = signature.blocks[index].len as u64;
+                    offset += len;
+                    on_progress(Progress::Matched(len))?;
+                }
+                Op::Literal(bytes) => {
+                    dest.seek(SeekFrom::Start(offset))?;
+                    dest.write_all(bytes)?;
+                    offset += bytes.len() as u64;
+                    on_progress(Progress::Literal(bytes.len() as u64))?;
+                }
+            }
+            Ok(())
+        })?;
+        dest.set_len(offset)?;
+        return dest.sync_all();
+    }
+    let mut old = fs::File::open(dest_path)?;
+    let mut buf = vec![0; block_size];
+    crate::atomic::write(dest_path, |temp| {
+        let mut temp = io::BufWriter::new(temp);
+        diff(source, &signature, false, |op| {
+            match op {
+                Op::Copy(index) => {
+                    let len = signature.blocks[index].len;
+                    old.seek(SeekFrom::Start((index * block_size) as u64))?;
+                    old.read_exact(&mut buf[0..len])?;
+                    temp.write_all(&buf[0..len])?;
+                    on_progress(Progress::Matched(len as u64))?;
+                }
+                Op::Literal(bytes) => {
+                    temp.write_all(bytes)?;
+                    on_progress(Progress::Literal(bytes.len() as u64))?;
+                }
+            }
+            Ok(())
+        })?;
+        temp.flush()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rebuild(old: &[u8], new: &[u8], aligned: bool) -> (Vec<u8>, u64) {
+        let block_size = 16;
+        let signature = Signature::build(old, block_size).unwrap();
+        let (mut out, mut matched) = (vec![], 0);
+        diff(new, &signature, aligned, |op| {
+            match op {
+                Op::Copy(index) => {
+                    let from = index * block_size;
+                    let to = (from + block_size).min(old.len());
+                    out.extend_from_slice(&old[from..to]);
+                    matched += (to - from) as u64;
+                }
+                Op::Literal(bytes) => out.extend_from_slice(bytes),
+            }
+            Ok(())
+        })
+        .unwrap();
+        (out, matched)
+    }
+
+    fn sample(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 31 % 251) as u8).collect()
+    }
+
+    #[test]
+    fn unchanged_file_is_fully_matched() {
+        let old = sample(1000);
+        assert_eq!(rebuild(&old, &old, false), (old.clone(), 1000));
+        assert_eq!(rebuild(&old, &old, true), (old, 1000));
+    }
+
+    #[test]
+    fn shifted_blocks_are_matched() {
+        let old = sample(1000);
+        let mut new = b"inserted".to_vec();
+        new.extend_from_slice(&old[0..500]);
+        new.extend_from_slice(b"changed in the middle");
+        new.extend_from_slice(&old[520..]);
+        let (out, matched) = rebuild(&old, &new, false);
+        assert_eq!(out, new);
+        assert!(matched >= 900);
+        // Aligned matching can not follow the shift.
+        let (out, matched) = rebuild(&old, &new, true);
+        assert_eq!(out, new);
+        assert_eq!(matched, 0);
+    }
+
+    #[test]
+    fn patch_in_place_and_via_temp_file() {
+        let dir = std::env::temp_dir().join(format!("cprs-delta-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (source, dest) = (dir.join("source"), dir.join("dest"));
+        let old = sample(1 << 16);
+        let mut new = old.clone();
+        new[1 << 14] ^= 0xff;
+        new.truncate(60000);
+        for inplace in [true, false] {
+            fs::write(&source, &new).unwrap();
+            fs::write(&dest, &old).unwrap();
+            let (mut literal, mut matched) = (0, 0);
+            patch(&source, &dest, inplace, |p| {
+                match p {
+                    Progress::Literal(n) => literal += n,
+                    Progress::Matched(n) => matched += n,
+                }
+                Ok(())
+            })
+            .unwrap();
+            assert_eq!(fs::read(&dest).unwrap(), new);
+            assert_eq!(literal + matched, new.len() as u64);
+            assert!(literal <= MIN_BLOCK_SIZE as u64 * 2);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
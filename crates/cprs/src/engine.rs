@@ -0,0 +1,500 @@
+//! Copy engines which move the bytes of one file to another.
+//!
+//! On linux the in-kernel engines (`reflink`, `copy_file_range` and `sendfile`) avoid copying every byte through
+//! userspace. `Auto` tries them in that order for each file and falls back to the next one when a file system does not
+//! support it, ending with the buffered read/write loop which works everywhere.
+//!
+//! Sparse files, which have fewer blocks allocated than their size needs, are copied one data range at a time with
+//! `SEEK_DATA`/`SEEK_HOLE` and their holes are left unwritten so that the destination stays sparse. Large files are
+//! preallocated with `fallocate` before they are written to keep them in fewer extents.
+use std::{
+    fs,
+    io::{self, Read, Seek, SeekFrom, Write},
+    str::FromStr,
+};
+
+const BUFFER_SIZE: usize = 1 << 18;
+// In-kernel copies are done in chunks of this size so that progress can still be reported.
+#[cfg(target_os = "linux")]
+const CHUNK_SIZE: usize = 1 << 23;
+/// Files with at least this many bytes of data are preallocated.
+const PREALLOCATE_MIN: u64 = 1 << 26;
+
+/// Represents the method of copying bytes of a file.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Engine {
+    /// Picks the first engine which works for each file.
+    #[default]
+    Auto,
+    /// Shares the extents of source with destination through `FICLONE` ioctl, only on btrfs/xfs like file systems.
+    Reflink,
+    /// `copy_file_range` system call, can be offloaded to the storage by some file systems.
+    CopyFileRange,
+    /// `sendfile` system call.
+    Sendfile,
+    /// Source is memory mapped and written to destination.
+    Mmap,
+    /// Userspace read/write loop.
+    Buffered,
+}
+
+impl FromStr for Engine {
+    fn from_str(engine: &str) -> Result<Self, Self::Err> {
+        match engine {
+            "auto" => Ok(Self::Auto),
+            "reflink" => Ok(Self::Reflink),
+            "copy-file-range" => Ok(Self::CopyFileRange),
+            "sendfile" => Ok(Self::Sendfile),
+            "mmap" => Ok(Self::Mmap),
+            "buffered" => Ok(Self::Buffered),
+            _ => Err(String::from("invalid engine")),
+        }
+    }
+
+    type Err = String;
+}
+
+// Reflink is tried first for the whole file, these are tried for each range after it.
+const AUTO_ORDER: [Engine; 3] = [Engine::CopyFileRange, Engine::Sendfile, Engine::Buffered];
+
+/// Bytes accounted while copying a file.
+#[derive(Debug)]
+pub enum Progress {
+    /// Bytes written to destination, which is what takes space on disk.
+    Data(u64),
+    /// Bytes of a hole in a sparse source which is kept as a hole in destination.
+    Hole(u64),
+}
+
+/// Errors which mean that an engine can not be used for given pair of files.
+fn is_unsupported(e: &io::Error) -> bool {
+    e.kind() == io::ErrorKind::Unsupported
+        || matches!(
+            e.raw_os_error(),
+            Some(
+                libc::EXDEV
+                    | libc::EINVAL
+                    | libc::ENOSYS
+                    | libc::EOPNOTSUPP
+                    | libc::ENOTTY
+                    | libc::EBADF
+            )
+        )
+}
+
+/// Copies `len` bytes of `source` to `dest`, `on_progress` is called after every chunk and for every hole, an error
+/// from it stops the copy. Returns the engine which was used. Fails if the size of source is not `len` anymore, as
+/// whatever was copied would not match it.
+pub fn copy(
+    source: &mut fs::File,
+    dest: &mut fs::File,
+    len: u64,
+    engine: Engine,
+    mut on_progress: impl FnMut(Progress) -> io::Result<()>,
+) -> io::Result<Engine> {
+    let ranges = data_ranges(source, len)?;
+    // Reflink shares the extents of the whole file, holes included, which are still reported as they are in source.
+    if matches!(engine, Engine::Auto | Engine::Reflink) {
+        match reflink(source, dest) {
+            Ok(()) => {
+                check_len(source, len)?;
+                let mut position = 0;
+                for (start, end) in ranges {
+                    if start > position {
+                        on_progress(Progress::Hole(start - position))?;
+                    }
+                    on_progress(Progress::Data(end - start))?;
+                    position = end;
+                }
+                if len > position {
+                    on_progress(Progress::Hole(len - position))?;
+                }
+                return Ok(Engine::Reflink);
+            }
+            Err(e) if engine == Engine::Auto && is_unsupported(&e) => {}
+            Err(e) => return Err(e),
+        }
+    }
+    if ranges.iter().map(|(start, end)| end - start).sum::<u64>() >= PREALLOCATE_MIN {
+        preallocate(dest, &ranges);
+    }
+    let (mut engine, mut position) = (engine, 0);
+    for (start, end) in ranges {
+        if start > position {
+            on_progress(Progress::Hole(start - position))?;
+        }
+        engine = copy_range(source, dest, start, end - start, engine, &mut |bytes| {
+            on_progress(Progress::Data(bytes))
+        })?;
+        position = end;
+    }
+    if len > position {
+        on_progress(Progress::Hole(len - position))?;
+    }
+    check_len(source, len)?;
+    // A hole at the end is not written, so it is only there once the size is set.
+    dest.set_len(len)?;
+    Ok(engine)
+}
+
+/// Error for a source which was written to while being copied.
+fn check_len(source: &fs::File, len: u64) -> io::Result<()> {
+    if source.metadata()?.len() != len {
+        return Err(io::Error::other("file changed size while copying"));
+    }
+    Ok(())
+}
+
+fn shorter() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::UnexpectedEof,
+        "file got shorter while copying",
+    )
+}
+
+/// Copies `len` bytes at `offset` of `source` to the same offset of `dest`. Returns the engine which was used, which
+/// is picked for this range if `engine` is `Auto`.
+fn copy_range(
+    source: &mut fs::File,
+    dest: &mut fs::File,
+    offset: u64,
+    len: u64,
+    engine: Engine,
+    on_copied: &mut dyn FnMut(u64) -> io::Result<()>,
+) -> io::Result<Engine> {
+    if engine != Engine::Auto {
+        source.seek(SeekFrom::Start(offset))?;
+        dest.seek(SeekFrom::Start(offset))?;
+        copy_with(source, dest, offset, len, engine, on_copied)?;
+        return Ok(engine);
+    }
+    for engine in AUTO_ORDER {
+        source.seek(SeekFrom::Start(offset))?;
+        dest.seek(SeekFrom::Start(offset))?;
+        let mut copied = 0;
+        match copy_with(source, dest, offset, len, engine, &mut |bytes| {
+            copied += bytes;
+            on_copied(bytes)
+        }) {
+            Ok(()) => return Ok(engine),
+            // Fall back only if nothing has been written yet, otherwise file offsets are not at the start anymore.
+            Err(e) if copied == 0 && is_unsupported(&e) => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    unreachable!("buffered engine never reports unsupported")
+}
+
+/// Copies `len` bytes from current offsets of both files, apart from mmap which reads from `offset` of source.
+fn copy_with(
+    source: &mut fs::File,
+    dest: &mut fs::File,
+    offset: u64,
+    len: u64,
+    engine: Engine,
+    on_copied: &mut dyn FnMut(u64) -> io::Result<()>,
+) -> io::Result<()> {
+    match engine {
+        Engine::CopyFileRange => copy_file_range(source, dest, len, on_copied),
+        Engine::Sendfile => sendfile(source, dest, len, on_copied),
+        Engine::Mmap => mmap(source, dest, offset, len, on_copied),
+        Engine::Buffered | Engine::Auto | Engine::Reflink => buffered(source, dest, len, on_copied),
+    }
+}
+
+/// Ranges of `source` which hold data, a file without holes is a single range. Holes are looked for only if fewer
+/// blocks are allocated for the file than its size needs.
+#[cfg(target_os = "linux")]
+fn data_ranges(source: &fs::File, len: u64) -> io::Result<Vec<(u64, u64)>> {
+    use std::os::{fd::AsRawFd, unix::fs::MetadataExt};
+    if source.metadata()?.blocks() * 512 >= len {
+        return Ok(vec![(0, len)]);
+    }
+    let fd = source.as_raw_fd();
+    let (mut ranges, mut offset) = (vec![], 0);
+    while offset < len {
+        // SAFETY: `lseek` only moves the offset of this open descriptor, which is set again before copying.
+        let start = unsafe { libc::lseek(fd, offset as libc::off_t, libc::SEEK_DATA) };
+        if start < 0 {
+            let e = io::Error::last_os_error();
+            return match e.raw_os_error() {
+                // No data after the offset, rest of the file is a hole.
+                Some(libc::ENXIO) => Ok(ranges),
+                // File system does not support looking for holes, so the file is copied whole.
+                Some(libc::EINVAL | libc::EOPNOTSUPP) => Ok(vec![(0, len)]),
+                _ => Err(e),
+            };
+        }
+        // SAFETY: same as above.
+        let end = unsafe { libc::lseek(fd, start, libc::SEEK_HOLE) };
+        if end < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let (start, end) = (start as u64, (end as u64).min(len));
+        if start >= end {
+            break;
+        }
+        ranges.push((start, end));
+        offset = end;
+    }
+    Ok(ranges)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn data_ranges(_: &fs::File, len: u64) -> io::Result<Vec<(u64, u64)>> {
+    Ok(vec![(0, len)])
+}
+
+/// Allocates blocks for the data ranges of `dest` without changing its size. It is only a hint for the file system to
+/// keep the file contiguous, so it is skipped where not supported.
+#[cfg(target_os = "linux")]
+fn preallocate(dest: &fs::File, ranges: &[(u64, u64)]) {
+    use std::os::fd::AsRawFd;
+    for (start, end) in ranges {
+        // SAFETY: `fallocate` only allocates blocks of this open descriptor.
+        unsafe {
+            libc::fallocate(
+                dest.as_raw_fd(),
+                libc::FALLOC_FL_KEEP_SIZE,
+                *start as libc::off_t,
+                (end - start) as libc::off_t,
+            )
+        };
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn preallocate(_: &fs::File, _: &[(u64, u64)]) {}
+
+fn buffered(
+    source: &mut fs::File,
+    dest: &mut fs::File,
+    len: u64,
+    on_copied: &mut dyn FnMut(u64) -> io::Result<()>,
+) -> io::Result<()> {
+    let mut buf = vec![0; BUFFER_SIZE.min(len as usize)];
+    let mut remaining = len;
+    while remaining > 0 {
+        let max = buf.len().min(remaining as usize);
+        let bytes_read = match source.read(&mut buf[..max]) {
+            Ok(0) => return Err(shorter()),
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        dest.write_all(&buf[0..bytes_read])?;
+        remaining -= bytes_read as u64;
+        on_copied(bytes_read as u64)?;
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn reflink(source: &fs::File, dest: &fs::File) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+    // SAFETY: both descriptors are open for the duration of the call.
+    if unsafe { libc::ioctl(dest.as_raw_fd(), libc::FICLONE, source.as_raw_fd()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Runs an in-kernel copy call, which advances both file offsets, till it copies `len` bytes. The call gets the most it
+/// should copy.
+#[cfg(target_os = "linux")]
+fn kernel_loop(
+    len: u64,
+    on_copied: &mut dyn FnMut(u64) -> io::Result<()>,
+    mut call: impl FnMut(usize) -> libc::ssize_t,
+) -> io::Result<()> {
+    let mut remaining = len;
+    while remaining > 0 {
+        match call(CHUNK_SIZE.min(remaining as usize)) {
+            0 => return Err(shorter()),
+            n if n > 0 => {
+                remaining -= n as u64;
+                on_copied(n as u64)?
+            }
+            _ => {
+                let e = io::Error::last_os_error();
+                if e.kind() != io::ErrorKind::Interrupted {
+                    return Err(e);
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn copy_file_range(
+    source: &fs::File,
+    dest: &fs::File,
+    len: u64,
+    on_copied: &mut dyn FnMut(u64) -> io::Result<()>,
+) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+    let (source, dest) = (source.as_raw_fd(), dest.as_raw_fd());
+    kernel_loop(len, on_copied, |max| {
+        // SAFETY: null offsets make the kernel use and advance the file offsets of these open descriptors.
+        unsafe {
+            libc::copy_file_range(
+                source,
+                std::ptr::null_mut(),
+                dest,
+                std::ptr::null_mut(),
+                max,
+                0,
+            )
+        }
+    })
+}
+
+#[cfg(target_os = "linux")]
+fn sendfile(
+    source: &fs::File,
+    dest: &fs::File,
+    len: u64,
+    on_copied: &mut dyn FnMut(u64) -> io::Result<()>,
+) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+    let (source, dest) = (source.as_raw_fd(), dest.as_raw_fd());
+    kernel_loop(len, on_copied, |max| {
+        // SAFETY: null offset makes the kernel use and advance the file offset of source descriptor.
+        unsafe { libc::sendfile(dest, source, std::ptr::null_mut(), max) }
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+fn reflink(_: &fs::File, _: &fs::File) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn copy_file_range(
+    _: &fs::File,
+    _: &fs::File,
+    _: u64,
+    _: &mut dyn FnMut(u64) -> io::Result<()>,
+) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn sendfile(
+    _: &fs::File,
+    _: &fs::File,
+    _: u64,
+    _: &mut dyn FnMut(u64) -> io::Result<()>,
+) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+fn mmap(
+    source: &fs::File,
+    dest: &mut fs::File,
+    offset: u64,
+    len: u64,
+    on_copied: &mut dyn FnMut(u64) -> io::Result<()>,
+) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+    if len == 0 {
+        return Ok(());
+    }
+    // Mapping starts at the page the range starts in, as its offset has to be page aligned.
+    // SAFETY: `sysconf` only reads a configuration value.
+    let page = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as u64;
+    let start = offset - offset % page;
+    let (offset, mapped) = ((offset - start) as usize, (offset + len - start) as usize);
+    // SAFETY: a fresh private read only mapping of an open descriptor, checked for failure below.
+    let addr = unsafe {
+        libc::mmap(
+            std::ptr::null_mut(),
+            mapped,
+            libc::PROT_READ,
+            libc::MAP_PRIVATE,
+            source.as_raw_fd(),
+            start as libc::off_t,
+        )
+    };
+    if addr == libc::MAP_FAILED {
+        return Err(io::Error::last_os_error());
+    }
+    // SAFETY: mapping is `mapped` bytes long and stays mapped till `munmap` below. Source being truncated meanwhile
+    // would raise SIGBUS, same as it would for any other mmap based copier.
+    let data = unsafe { std::slice::from_raw_parts(addr as *const u8, mapped) };
+    let result = data[offset..].chunks(BUFFER_SIZE).try_for_each(|chunk| {
+        dest.write_all(chunk)?;
+        on_copied(chunk.len() as u64)
+    });
+    // SAFETY: `addr` and `mapped` are exactly what `mmap` returned and `data` is not used anymore.
+    unsafe { libc::munmap(addr, mapped) };
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::MetadataExt;
+
+    #[test]
+    fn sparse_file_keeps_its_holes() {
+        let dir = std::env::temp_dir().join(format!("cprs-engine-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let (source_path, dest_path) = (dir.join("source"), dir.join("dest"));
+        let len = 16 << 20;
+        let mut source = fs::File::create(&source_path).unwrap();
+        source.set_len(len).unwrap();
+        source.seek(SeekFrom::Start(4 << 20)).unwrap();
+        source.write_all(&[7; 1 << 20]).unwrap();
+        drop(source);
+
+        for engine in [Engine::Buffered, Engine::Mmap, Engine::Auto] {
+            let mut source = fs::File::open(&source_path).unwrap();
+            let mut dest = fs::File::create(&dest_path).unwrap();
+            let (mut data, mut holes) = (0, 0);
+            copy(&mut source, &mut dest, len, engine, |progress| {
+                match progress {
+                    Progress::Data(bytes) => data += bytes,
+                    Progress::Hole(bytes) => holes += bytes,
+                }
+                Ok(())
+            })
+            .unwrap();
+            assert_eq!(
+                fs::read(&dest_path).unwrap(),
+                fs::read(&source_path).unwrap()
+            );
+            assert_eq!(data + holes, len);
+            // File systems without hole support copy the whole file, which is still correct.
+            if fs::metadata(&source_path).unwrap().blocks() * 512 < len {
+                assert_eq!(data, 1 << 20);
+                assert!(fs::metadata(&dest_path).unwrap().blocks() * 512 < len);
+            }
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn source_changing_size_fails_the_copy() {
+        let dir = std::env::temp_dir().join(format!("cprs-engine-len-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let (source_path, dest_path) = (dir.join("source"), dir.join("dest"));
+        fs::write(&source_path, [7; 1000]).unwrap();
+        // Size from before the source was truncated or appended to.
+        for (len, kind) in [
+            (2000, io::ErrorKind::UnexpectedEof),
+            (500, io::ErrorKind::Other),
+        ] {
+            for engine in [Engine::Buffered, Engine::Auto] {
+                let mut source = fs::File::open(&source_path).unwrap();
+                let mut dest = fs::File::create(&dest_path).unwrap();
+                let e = copy(&mut source, &mut dest, len, engine, |_| Ok(())).unwrap_err();
+                assert_eq!(e.kind(), kind);
+            }
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,369 @@
+//! Events produced while transferring paths and the state aggregated from them.
+use std::{
+    fmt,
+    sync::{atomic::AtomicU64, Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+
+use crate::{control::Switch, throttle, ui};
+
+#[derive(Debug, PartialEq, Eq, Serialize)]
+#[serde(tag = "type", content = "data", rename_all = "snake_case")]
+pub enum EventType {
+    DataCopied(u64),
+    DataMatched(u64),
+    /// Bytes of a hole in a sparse file which were left as a hole at destination instead of being written.
+    HoleSkipped(u64),
+    /// Symlink created with given target.
+    SymlinkCreated(String),
+    /// File created as a hard link of given destination path instead of copying its bytes.
+    HardLinked(String, u64),
+    SpecialCreated,
+    SpecialSkipped,
+    /// Worker waited for given microseconds to keep the rate limits.
+    Throttled(u64),
+    /// Copied, matched and hole bytes reported by an attempt which failed, these are taken back from the progress.
+    Reverted(u64, u64, u64),
+    /// Attempt failed with a transient error and this retry number is started after a backoff.
+    Retrying(u32, String),
+    /// Path could not be transferred for given reason, rest of the transfer carries on.
+    Failed(String),
+    /// Transfer was cancelled while this path was in flight. Its temp file is removed, so the destination is left as
+    /// it was before, apart from an in place delta transfer (`--inplace`) which leaves it partly patched.
+    Cancelled,
+    /// Temp file left at destination by an interrupted run was removed, path is of the temp file.
+    TempRemoved,
+    /// Transfer of a path which is not a directory is started, with its size.
+    PathStarted(u64),
+    PathCompleted,
+}
+
+impl EventType {
+    /// Bytes of the path this event accounts for.
+    pub fn bytes(&self) -> u64 {
+        match self {
+            EventType::DataCopied(bytes)
+            | EventType::DataMatched(bytes)
+            | EventType::HoleSkipped(bytes)
+            | EventType::HardLinked(_, bytes)
+            | EventType::PathStarted(bytes) => *bytes,
+            EventType::Reverted(copied, matched, holes) => copied + matched + holes,
+            _ => 0,
+        }
+    }
+}
+
+/// Event along with the state right after applying it, this is what callers of `Copier` receive.
+pub type Update = (Event, Arc<State>);
+
+#[derive(Debug)]
+pub struct Event {
+    /// Source path the event is for.
+    pub path: String,
+    pub event_type: EventType,
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let out = match &self.event_type {
+            EventType::PathStarted(_) => format!("Started `{}`.", self.path),
+            EventType::PathCompleted => format!("Completed `{}`.", self.path),
+            EventType::SymlinkCreated(target) => {
+                format!("Linked `{}` -> `{}`.", self.path, target)
+            }
+            EventType::HardLinked(original, _) => {
+                format!("Hard linked `{}` to `{}`.", self.path, original)
+            }
+            EventType::SpecialCreated => format!("Created special file `{}`.", self.path),
+            EventType::SpecialSkipped => format!("Skipped special file `{}`.", self.path),
+            EventType::Retrying(retry, reason) => {
+                format!("Retrying `{}` ({}) after - {}.", self.path, retry, reason)
+            }
+            EventType::Failed(reason) => format!("Failed `{}` - {}.", self.path, reason),
+            EventType::Cancelled => format!("Cancelled `{}`.", self.path),
+            EventType::TempRemoved => format!("Removed stale temp file `{}`.", self.path),
+            EventType::DataCopied(_)
+            | EventType::DataMatched(_)
+            | EventType::Throttled(_)
+            | EventType::HoleSkipped(_)
+            | EventType::Reverted(_, _, _) => {
+                format!("Copying `{}`.", self.path)
+            }
+        };
+        f.write_str(out.as_str())
+    }
+}
+
+/// Progress of a transfer, totals are known from the plan and rest of it is updated from the events.
+#[derive(Debug)]
+pub struct State {
+    pub total_count: u64,
+    pub file_count: u64,
+    pub folder_count: u64,
+    pub total_bytes: u64,
+    pub(crate) copied_file_count: AtomicU64,
+    pub(crate) copied_bytes: AtomicU64,
+    pub(crate) matched_bytes: AtomicU64,
+    pub(crate) hole_bytes: AtomicU64,
+    pub(crate) linked_bytes: AtomicU64,
+    /// Paths which were in flight when the transfer was cancelled.
+    pub(crate) cancelled_count: AtomicU64,
+    pub(crate) throttled_micros: AtomicU64,
+    pub(crate) throttle: Arc<throttle::Throttle>,
+    pub(crate) switch: Arc<Switch>,
+    /// Paths which could not be transferred along with the reason.
+    pub(crate) failures: Mutex<Vec<(String, String)>>,
+    pub(crate) started_at: Instant,
+    pub(crate) throughput: Mutex<ui::Throughput>,
+    /// Files in flight, one for each busy worker, and the slowest and largest ones done so far.
+    pub(crate) files: Mutex<ui::Files>,
+}
+
+impl State {
+    /// Logical bytes which are present at destination, either copied as literal data, matched by delta transfer,
+    /// shared through a hard link or kept as holes of sparse files.
+    pub fn done_bytes(&self) -> u64 {
+        self.copied_bytes.load(std::sync::atomic::Ordering::Relaxed)
+            + self
+                .matched_bytes
+                .load(std::sync::atomic::Ordering::Relaxed)
+            + self.linked_bytes.load(std::sync::atomic::Ordering::Relaxed)
+            + self.hole_bytes.load(std::sync::atomic::Ordering::Relaxed)
+    }
+
+    /// Paths which could not be transferred along with the reason.
+    pub fn failures(&self) -> Vec<(String, String)> {
+        self.failures.lock().unwrap().clone()
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.switch.is_paused()
+    }
+
+    /// Takes the bytes done so far into the throughput, which every event does as well. Renderers call this on their
+    /// own ticks too, so that the rate falls while a transfer is stalled, throttled or paused and sends no events.
+    pub fn sample_throughput(&self) {
+        self.throughput.lock().unwrap().sample(self.done_bytes());
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.switch.is_cancelled()
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        let done_bytes = self.done_bytes();
+        let bytes_per_sec = self.throughput.lock().unwrap().rate();
+        let files = self.files.lock().unwrap();
+        let mut in_flight = files
+            .in_flight
+            .iter()
+            .map(|(path, file)| InFlightSnapshot {
+                path: path.clone(),
+                size: file.size,
+                done: file.done,
+                elapsed_secs: file.started_at.elapsed().as_secs_f64(),
+            })
+            .collect::<Vec<InFlightSnapshot>>();
+        in_flight.sort_by(|a, b| b.elapsed_secs.total_cmp(&a.elapsed_secs));
+        Snapshot {
+            total_count: self.total_count,
+            file_count: self.file_count,
+            folder_count: self.folder_count,
+            total_bytes: self.total_bytes,
+            copied_file_count: self
+                .copied_file_count
+                .load(std::sync::atomic::Ordering::Relaxed),
+            copied_bytes: self.copied_bytes.load(std::sync::atomic::Ordering::Relaxed),
+            matched_bytes: self
+                .matched_bytes
+                .load(std::sync::atomic::Ordering::Relaxed),
+            linked_bytes: self.linked_bytes.load(std::sync::atomic::Ordering::Relaxed),
+            hole_bytes: self.hole_bytes.load(std::sync::atomic::Ordering::Relaxed),
+            done_bytes,
+            failed_count: self.failures.lock().unwrap().len() as u64,
+            cancelled_count: self
+                .cancelled_count
+                .load(std::sync::atomic::Ordering::Relaxed),
+            throttled_micros: self
+                .throttled_micros
+                .load(std::sync::atomic::Ordering::Relaxed),
+            elapsed_secs: self.started_at.elapsed().as_secs_f64(),
+            paused: self.switch.is_paused(),
+            cancelled: self.switch.is_cancelled(),
+            bytes_per_sec,
+            // Nothing gets done while paused, so there is no telling when it will be.
+            eta_secs: bytes_per_sec
+                .filter(|rate| *rate > 0.0 && !self.switch.is_paused())
+                .map(|rate| self.total_bytes.saturating_sub(done_bytes) as f64 / rate),
+            in_flight,
+        }
+    }
+}
+
+/// Point in time copy of `State` which can be serialized.
+#[derive(Debug, Serialize)]
+pub struct Snapshot {
+    pub total_count: u64,
+    pub file_count: u64,
+    pub folder_count: u64,
+    pub total_bytes: u64,
+    pub copied_file_count: u64,
+    /// Physical bytes, which are the ones written to destination.
+    pub copied_bytes: u64,
+    pub matched_bytes: u64,
+    pub linked_bytes: u64,
+    pub hole_bytes: u64,
+    /// Logical bytes, which add up to `total_bytes` once everything is copied.
+    pub done_bytes: u64,
+    pub failed_count: u64,
+    /// Paths which were in flight when the transfer was cancelled, paths not started by then are not counted.
+    pub cancelled_count: u64,
+    pub throttled_micros: u64,
+    pub elapsed_secs: f64,
+    pub paused: bool,
+    pub cancelled: bool,
+    /// Smoothed throughput, not known till the first sample.
+    pub bytes_per_sec: Option<f64>,
+    pub eta_secs: Option<f64>,
+    /// Files being transferred, longest running first.
+    pub in_flight: Vec<InFlightSnapshot>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct InFlightSnapshot {
+    pub path: String,
+    pub size: u64,
+    pub done: u64,
+    pub elapsed_secs: f64,
+}
+
+impl fmt::Display for State {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let width = ui::terminal_width();
+        let mut lines = vec![];
+        let total_data = self.total_bytes;
+        let copied_data = self.done_bytes();
+        let literal_data = self.copied_bytes.load(std::sync::atomic::Ordering::Relaxed);
+        let matched_data = self
+            .matched_bytes
+            .load(std::sync::atomic::Ordering::Relaxed);
+        let total_files = self.file_count;
+        let copied_files = self
+            .copied_file_count
+            .load(std::sync::atomic::Ordering::Relaxed);
+        let progress_data = if total_data > 0 {
+            copied_data as f64 / total_data as f64
+        } else {
+            0.0
+        };
+        lines.push(ui::bar(progress_data, width));
+        lines.push(format!("- Files - {} / {}", copied_files, total_files));
+        lines.push(format!(
+            "- Data - {} / {} ({:.2}%)",
+            ui::bytes(copied_data),
+            ui::bytes(total_data),
+            progress_data * 100.0
+        ));
+        let elapsed = self.started_at.elapsed();
+        let speed = match self.throughput.lock().unwrap().rate() {
+            Some(rate) if self.switch.is_paused() => {
+                format!("{}/s, ETA unknown while paused", ui::bytes(rate as u64))
+            }
+            Some(rate) if rate > 0.0 => {
+                let eta = total_data.saturating_sub(copied_data) as f64 / rate;
+                format!(
+                    "{}/s, ETA {}",
+                    ui::bytes(rate as u64),
+                    ui::duration(Duration::from_secs_f64(eta))
+                )
+            }
+            Some(_) => String::from("stalled"),
+            None => String::from("measuring"),
+        };
+        lines.push(format!(
+            "- Speed - {} (elapsed {})",
+            speed,
+            ui::duration(elapsed)
+        ));
+        let hole_data = self.hole_bytes.load(std::sync::atomic::Ordering::Relaxed);
+        if hole_data > 0 {
+            lines.push(format!(
+                "- Sparse - written {} / kept as holes {}",
+                ui::bytes(literal_data),
+                ui::bytes(hole_data),
+            ));
+        }
+        if matched_data > 0 {
+            lines.push(format!(
+                "- Delta - literal {} / matched {}",
+                ui::bytes(literal_data),
+                ui::bytes(matched_data),
+            ));
+        }
+        let (bytes_per_sec, files_per_sec) = self.throttle.limits();
+        let throttled = self
+            .throttled_micros
+            .load(std::sync::atomic::Ordering::Relaxed);
+        if bytes_per_sec.is_some() || files_per_sec.is_some() || throttled > 0 {
+            let limit = |l: Option<u64>| l.map_or(String::from("off"), |l| l.to_string());
+            lines.push(format!(
+                "- Throttle - {} bytes/s, {} files/s (waited {:.1}s across workers)",
+                limit(bytes_per_sec),
+                limit(files_per_sec),
+                throttled as f64 / 1e6,
+            ));
+        }
+        let failed = self.failures.lock().unwrap().len();
+        if failed > 0 {
+            lines.push(format!("- Failed - {}", failed));
+        }
+        if self.switch.is_cancelled() {
+            lines.push(String::from(
+                "- Cancelled - finishing current chunks and removing temp files",
+            ));
+        } else if self.switch.is_paused() {
+            lines.push(String::from("- Paused -"));
+        }
+        let files = self.files.lock().unwrap();
+        if !files.in_flight.is_empty() {
+            lines.push(String::from("- Workers -"));
+            let mut in_flight = files.in_flight.iter().collect::<Vec<_>>();
+            in_flight.sort_by_key(|(_, file)| file.started_at);
+            for (path, file) in in_flight {
+                let percent = if file.size > 0 {
+                    file.done as f64 * 100.0 / file.size as f64
+                } else {
+                    100.0
+                };
+                lines.push(ui::path_line(
+                    &format!("  [{:>3.0}%] ", percent.min(100.0)),
+                    path,
+                    &format!(
+                        " ({}, {})",
+                        ui::bytes(file.size),
+                        ui::duration(file.started_at.elapsed())
+                    ),
+                    width,
+                ));
+            }
+        }
+        if !files.slowest.is_empty() {
+            lines.push(String::from("- Slowest -"));
+            for (path, took) in &files.slowest {
+                let suffix = format!(" ({})", ui::duration(*took));
+                lines.push(ui::path_line("  ", path, &suffix, width));
+            }
+            lines.push(String::from("- Largest -"));
+            for (path, size) in &files.largest {
+                let suffix = format!(" ({})", ui::bytes(*size));
+                lines.push(ui::path_line("  ", path, &suffix, width));
+            }
+        }
+        for line in lines {
+            writeln!(f, "{}", ui::fit(&line, width))?;
+        }
+        Ok(())
+    }
+}
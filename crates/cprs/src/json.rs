@@ -0,0 +1,77 @@
+//! JSON lines output for other programs to follow a transfer.
+//!
+//! Every line is one JSON object with a `record` field telling what it is -
+//! - `event` - one `Event` with its `path`, the `event` itself and the `bytes` it accounts for.
+//! - `state` - snapshot of `State`, written periodically and once at the end.
+//! - `summary` - written last, with the failed paths and their reasons.
+//! - `plan` - one entry of a dry run.
+use std::{
+    io::{self, Write},
+    sync::mpsc::Receiver,
+    time::{Duration, Instant},
+};
+
+use serde_json::{json, Value};
+
+use cprs::{PlanEntry, State, Update};
+
+const SNAPSHOT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Write errors are ignored so that a consumer which stops reading does not stop the transfer.
+fn write(record: Value) {
+    let mut stdout = io::stdout().lock();
+    let _ = serde_json::to_writer(&mut stdout, &record).map(|_| stdout.write_all(b"\n"));
+}
+
+/// Writes every event received and a state snapshot once every second till the transfer is done.
+pub fn render(event_receiver: &Receiver<Update>) {
+    let mut last_ts = Instant::now();
+    while let Ok((event, state)) = event_receiver.recv() {
+        write(json!({
+            "record": "event",
+            "path": event.path,
+            "event": event.event_type,
+            "bytes": event.event_type.bytes(),
+        }));
+        if last_ts.elapsed() >= SNAPSHOT_INTERVAL {
+            write_state(&state);
+            last_ts = Instant::now();
+        }
+    }
+}
+
+pub fn write_state(state: &State) {
+    let mut record = json!(state.snapshot());
+    record["record"] = json!("state");
+    write(record);
+}
+
+/// Writes the final state and the summary.
+pub fn write_summary(state: &State) {
+    write_state(state);
+    let snapshot = state.snapshot();
+    let failures = state.failures();
+    write(json!({
+        "record": "summary",
+        "completed": snapshot.copied_file_count,
+        "failed": failures.len(),
+        "failures": failures
+            .iter()
+            .map(|(path, reason)| json!({ "path": path, "reason": reason }))
+            .collect::<Vec<Value>>(),
+        "cancelled": snapshot.cancelled,
+        "cancelled_count": snapshot.cancelled_count,
+        "done_bytes": snapshot.done_bytes,
+        "total_bytes": snapshot.total_bytes,
+        "elapsed_secs": snapshot.elapsed_secs,
+    }));
+}
+
+pub fn write_plan(plan: &[PlanEntry], state: &State) {
+    for entry in plan {
+        let mut record = json!(entry);
+        record["record"] = json!("plan");
+        write(record);
+    }
+    write_state(state);
+}
@@ -0,0 +1,111 @@
+//! Keys and commands read from stdin while copying.
+//!
+//! When stdin is a terminal it is switched to read key by key, so that `p` toggles pause, `q` or `Ctrl-C` cancels and
+//! a second `Ctrl-C` quits right away. Lines are still read in both cases - `bwlimit <limit>` and `files <limit>`
+//! followed by enter. `pause`, `resume` and `cancel` lines are for when stdin is not a terminal, as a `p` starting a
+//! line in one is taken as the key.
+use std::{
+    io::{self, Read},
+    process,
+    str::FromStr,
+    sync::{mpsc::Sender, Arc},
+};
+
+use cprs::{Control, Limit, State};
+
+const CTRL_C: u8 = 3;
+
+/// Terminal settings of stdin before they were changed, restored when dropped.
+pub struct RawMode(libc::termios);
+
+impl RawMode {
+    /// Turns off line buffering, echo and signals of stdin, `None` if it is not a terminal.
+    pub fn enable() -> Option<Self> {
+        // SAFETY: `termios` is plain data for which zeroes are valid, `tcgetattr` only writes into it.
+        let mut termios: libc::termios = unsafe { std::mem::zeroed() };
+        // SAFETY: `termios` lives through the call.
+        if unsafe { libc::tcgetattr(libc::STDIN_FILENO, &mut termios) } != 0 {
+            return None;
+        }
+        let original = termios;
+        termios.c_lflag &= !(libc::ICANON | libc::ECHO | libc::ISIG);
+        termios.c_cc[libc::VMIN] = 1;
+        termios.c_cc[libc::VTIME] = 0;
+        // SAFETY: `termios` is a valid setting read above with only the flags changed.
+        if unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &termios) } != 0 {
+            return None;
+        }
+        Some(RawMode(original))
+    }
+
+    pub fn original(&self) -> libc::termios {
+        self.0
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        restore(&self.0);
+    }
+}
+
+fn restore(termios: &libc::termios) {
+    // SAFETY: `termios` is the setting read from stdin before it was changed.
+    unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, termios) };
+}
+
+fn parse_line(line: &str) -> Option<Control> {
+    match line.split_whitespace().collect::<Vec<&str>>().as_slice() {
+        ["bwlimit", limit] => Limit::from_str(limit)
+            .ok()
+            .map(|l| Control::BytesPerSec(l.0)),
+        ["files", limit] => Limit::from_str(limit)
+            .ok()
+            .map(|l| Control::FilesPerSec(l.0)),
+        ["pause"] => Some(Control::Pause),
+        ["resume"] => Some(Control::Resume),
+        ["cancel"] => Some(Control::Cancel),
+        _ => None,
+    }
+}
+
+/// Reads stdin till it is closed, sending the controls it reads. `raw_mode` is the terminal setting to restore if the
+/// process is quit with a second `Ctrl-C`.
+pub fn listen(controls: Sender<Control>, state: Arc<State>, raw_mode: Option<libc::termios>) {
+    let mut line = String::new();
+    for byte in io::stdin().lock().bytes() {
+        let Ok(byte) = byte else {
+            return;
+        };
+        let control = match byte {
+            CTRL_C if state.is_cancelled() => {
+                if let Some(termios) = &raw_mode {
+                    restore(termios);
+                }
+                process::exit(130);
+            }
+            CTRL_C => Some(Control::Cancel),
+            // Single keys are taken only in a terminal and only when no command is being typed.
+            b'p' if raw_mode.is_some() && line.is_empty() && state.is_paused() => {
+                Some(Control::Resume)
+            }
+            b'p' if raw_mode.is_some() && line.is_empty() => Some(Control::Pause),
+            b'q' if raw_mode.is_some() && line.is_empty() => Some(Control::Cancel),
+            b'\n' | b'\r' => parse_line(&std::mem::take(&mut line)),
+            // Backspace, as echo is off there is no need to erase it from the screen.
+            127 | 8 => {
+                line.pop();
+                None
+            }
+            _ => {
+                line.push(byte as char);
+                None
+            }
+        };
+        if let Some(control) = control {
+            if controls.send(control).is_err() {
+                return;
+            }
+        }
+    }
+}
@@ -0,0 +1,53 @@
+/*!
+# cprs
+Copies a tree of files from a source to a destination directory with progress reporting, delta transfer of files
+which already exist at destination, rate limits and retries. The `cprs` binary is one consumer of this library, it
+renders the events in a terminal or writes them as JSON lines.
+
+# Example
+```no_run
+use cprs::{Copier, Options, Strategy};
+
+let copier = Copier::new(
+    win_tree::Config {
+        path: String::from("./source"),
+        depth_check: None,
+        exclude_pattern: None,
+        build_method: win_tree::BuildMethod::ParallelRayon,
+        follow_symlinks: false,
+    },
+    String::from("./dest"),
+    Strategy::default(),
+    Options::default(),
+)
+.expect("unable to plan the transfer");
+let handle = copier.start();
+for (event, state) in handle.events() {
+    println!("{event} - {} / {} bytes", state.done_bytes(), state.total_bytes);
+}
+let state = handle.join();
+assert!(state.failures().is_empty());
+```
+*/
+
+mod archive;
+mod atomic;
+mod control;
+mod copier;
+mod delta;
+mod engine;
+mod event;
+mod metadata;
+mod retry;
+mod special;
+mod throttle;
+mod ui;
+
+pub use archive::Format;
+pub use control::Control;
+pub use copier::{Action, Copier, Handle, Options, PlanEntry, Strategy};
+pub use engine::Engine;
+pub use event::{Event, EventType, InFlightSnapshot, Snapshot, State, Update};
+pub use metadata::Preserve;
+pub use special::{Specials, Symlinks};
+pub use throttle::Limit;
@@ -0,0 +1,116 @@
+use std::{
+    ffi::CString,
+    fs, io,
+    os::unix::{
+        ffi::OsStrExt,
+        fs::{MetadataExt, PermissionsExt},
+    },
+    path::Path,
+    str::FromStr,
+};
+
+/// Represents which metadata of source paths is carried over to the destination.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Preserve {
+    /// Permission bits.
+    pub mode: bool,
+    /// Access and modification times.
+    pub timestamps: bool,
+    /// Owner user and group, only applied when running as root.
+    pub ownership: bool,
+    /// Extended attributes.
+    pub xattrs: bool,
+}
+
+impl Preserve {
+    pub fn any(&self) -> bool {
+        self.mode || self.timestamps || self.ownership || self.xattrs
+    }
+}
+
+impl FromStr for Preserve {
+    fn from_str(list: &str) -> Result<Self, Self::Err> {
+        let mut preserve = Preserve::default();
+        for item in list.split(',') {
+            match item {
+                "mode" => preserve.mode = true,
+                "timestamps" => preserve.timestamps = true,
+                "ownership" => preserve.ownership = true,
+                "xattrs" => preserve.xattrs = true,
+                "all" => {
+                    preserve = Preserve {
+                        mode: true,
+                        timestamps: true,
+                        ownership: true,
+                        xattrs: true,
+                    }
+                }
+                _ => return Err(format!("invalid preserve option `{item}`")),
+            }
+        }
+        Ok(preserve)
+    }
+
+    type Err = String;
+}
+
+fn set_times(dest: &Path, metadata: &fs::Metadata) -> io::Result<()> {
+    let path = CString::new(dest.as_os_str().as_bytes())?;
+    let times = [
+        libc::timespec {
+            tv_sec: metadata.atime(),
+            tv_nsec: metadata.atime_nsec(),
+        },
+        libc::timespec {
+            tv_sec: metadata.mtime(),
+            tv_nsec: metadata.mtime_nsec(),
+        },
+    ];
+    // SAFETY: `path` is a valid nul terminated string and `times` has the two entries `utimensat` reads.
+    let res = unsafe {
+        libc::utimensat(
+            libc::AT_FDCWD,
+            path.as_ptr(),
+            times.as_ptr(),
+            libc::AT_SYMLINK_NOFOLLOW,
+        )
+    };
+    if res != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Copies the selected metadata of `source` to `dest`.
+///
+/// Order matters here - `chown` clears setuid bits so it goes before `chmod`. Symlinks do not have a mode of their
+/// own and only regular files and directories can have user xattrs, so those are skipped for the rest.
+pub fn apply(source: &Path, dest: &Path, preserve: &Preserve) -> io::Result<()> {
+    if !preserve.any() {
+        return Ok(());
+    }
+    let mut metadata = fs::symlink_metadata(source)?;
+    if metadata.is_symlink() && !fs::symlink_metadata(dest)?.is_symlink() {
+        // Source link was followed while copying.
+        metadata = fs::metadata(source)?;
+    }
+    let is_symlink = metadata.is_symlink();
+    if preserve.xattrs && (metadata.is_file() || metadata.is_dir()) {
+        for name in xattr::list_deref(source)? {
+            if let Some(value) = xattr::get_deref(source, &name)? {
+                xattr::set(dest, &name, &value)?;
+            }
+        }
+    }
+    // SAFETY: `geteuid` has no preconditions and can not fail.
+    if preserve.ownership && unsafe { libc::geteuid() } == 0 {
+        std::os::unix::fs::lchown(dest, Some(metadata.uid()), Some(metadata.gid()))?;
+    }
+    if preserve.mode && !is_symlink {
+        fs::set_permissions(dest, fs::Permissions::from_mode(metadata.mode()))?;
+    }
+    if preserve.timestamps {
+        set_times(dest, &metadata)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,25 @@
+//! Retrying of failed transfers.
+//!
+//! Only errors which can go away on their own are retried, like an interrupted call or a busy or flaky device. Rest of
+//! them, like a missing or unreadable file, fail the path right away.
+use std::{io, time::Duration};
+
+const INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_BACKOFF: Duration = Duration::from_secs(10);
+
+pub fn is_transient(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+    ) || matches!(
+        e.raw_os_error(),
+        Some(libc::EAGAIN | libc::EBUSY | libc::EIO | libc::ETIMEDOUT | libc::ESTALE)
+    )
+}
+
+/// Time to wait before given retry, starting from 1. It doubles for every retry up to a maximum.
+pub fn backoff(retry: u32) -> Duration {
+    INITIAL_BACKOFF
+        .saturating_mul(1 << retry.saturating_sub(1).min(16))
+        .min(MAX_BACKOFF)
+}
@@ -0,0 +1,166 @@
+//! Handling of paths which are neither regular files nor directories - symlinks, hard links and special files.
+use std::{
+    collections::HashMap,
+    ffi::CString,
+    fs, io,
+    os::unix::{ffi::OsStrExt, fs::MetadataExt},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Condvar, Mutex},
+};
+
+/// Represents how symlinks in source are transferred.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Symlinks {
+    /// Create a symlink with the same target at destination.
+    #[default]
+    Copy,
+    /// Copy whatever the symlink points to.
+    Follow,
+}
+
+impl FromStr for Symlinks {
+    fn from_str(mode: &str) -> Result<Self, Self::Err> {
+        match mode {
+            "copy" => Ok(Self::Copy),
+            "follow" => Ok(Self::Follow),
+            _ => Err(String::from("invalid symlinks mode")),
+        }
+    }
+
+    type Err = String;
+}
+
+/// Represents how FIFOs, sockets and device nodes in source are transferred.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Specials {
+    /// Recreate the node at destination, device nodes need root for this.
+    #[default]
+    Create,
+    Skip,
+}
+
+impl FromStr for Specials {
+    fn from_str(mode: &str) -> Result<Self, Self::Err> {
+        match mode {
+            "create" => Ok(Self::Create),
+            "skip" => Ok(Self::Skip),
+            _ => Err(String::from("invalid specials mode")),
+        }
+    }
+
+    type Err = String;
+}
+
+fn remove_existing(dest: &Path) -> io::Result<()> {
+    match fs::remove_file(dest) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// Creates a symlink at `dest` with same target as `source` and returns the target.
+pub fn copy_symlink(source: &Path, dest: &Path) -> io::Result<PathBuf> {
+    let target = fs::read_link(source)?;
+    create_symlink(&target, dest)?;
+    Ok(target)
+}
+
+/// Creates a symlink to `target` at `dest`, replacing whatever is there.
+pub fn create_symlink(target: &Path, dest: &Path) -> io::Result<()> {
+    remove_existing(dest)?;
+    std::os::unix::fs::symlink(target, dest)
+}
+
+/// Recreates FIFO, socket or device node `source` at `dest`.
+pub fn create_special(source: &Path, dest: &Path) -> io::Result<()> {
+    let metadata = fs::symlink_metadata(source)?;
+    remove_existing(dest)?;
+    let path = CString::new(dest.as_os_str().as_bytes())?;
+    // SAFETY: `path` is a valid nul terminated string which outlives the call.
+    if unsafe { libc::mknod(path.as_ptr(), metadata.mode(), metadata.rdev()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+enum LinkState {
+    Copying,
+    Done(PathBuf),
+    Failed,
+}
+
+/// Keeps track of files with multiple hard links so that only first of them is copied and the rest are linked to it.
+#[derive(Default)]
+pub struct HardLinks {
+    inodes: Mutex<HashMap<(u64, u64), LinkState>>,
+    copied: Condvar,
+}
+
+/// Result of registering a file with `HardLinks`.
+pub enum Link<'a> {
+    /// File is not linked or it is the first one seen from its links, it must be copied and then completed.
+    Original(LinkClaim<'a>),
+    /// File is a link to this already copied destination.
+    To(PathBuf),
+}
+
+/// Claim of copying an inode, links waiting on it are released once this is completed or dropped.
+pub struct LinkClaim<'a> {
+    links: &'a HardLinks,
+    key: Option<(u64, u64)>,
+}
+
+impl LinkClaim<'_> {
+    pub fn complete(mut self, dest: &Path) {
+        self.finish(LinkState::Done(dest.to_path_buf()));
+    }
+
+    fn finish(&mut self, state: LinkState) {
+        if let Some(key) = self.key.take() {
+            self.links.inodes.lock().unwrap().insert(key, state);
+            self.links.copied.notify_all();
+        }
+    }
+}
+
+impl Drop for LinkClaim<'_> {
+    fn drop(&mut self) {
+        self.finish(LinkState::Failed);
+    }
+}
+
+impl HardLinks {
+    /// Registers source file with its `lstat` metadata. If another link of the same inode is being copied, this blocks
+    /// till that copy is done. If that copy failed, this file becomes the original instead. Followed symlinks are
+    /// always copied on their own.
+    pub fn register(&self, metadata: &fs::Metadata) -> Link<'_> {
+        if metadata.is_symlink() || metadata.nlink() < 2 {
+            return Link::Original(LinkClaim {
+                links: self,
+                key: None,
+            });
+        }
+        let key = (metadata.dev(), metadata.ino());
+        let mut inodes = self.inodes.lock().unwrap();
+        loop {
+            match inodes.get(&key) {
+                Some(LinkState::Copying) => inodes = self.copied.wait(inodes).unwrap(),
+                Some(LinkState::Done(dest)) => return Link::To(dest.clone()),
+                Some(LinkState::Failed) | None => {
+                    inodes.insert(key, LinkState::Copying);
+                    return Link::Original(LinkClaim {
+                        links: self,
+                        key: Some(key),
+                    });
+                }
+            }
+        }
+    }
+}
+
+/// Creates `dest` as a hard link of `original`, replacing whatever was at `dest`.
+pub fn hard_link(original: &Path, dest: &Path) -> io::Result<()> {
+    remove_existing(dest)?;
+    fs::hard_link(original, dest)
+}
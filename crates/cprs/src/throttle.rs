@@ -0,0 +1,136 @@
+//! Rate limiting shared by all transfer workers.
+//!
+//! Each limit is a token bucket which refills at its rate and holds at most one second worth of tokens. A worker takes
+//! tokens for what it just did and sleeps off any debt outside the lock, so a chunk bigger than the bucket simply makes
+//! the worker wait longer instead of getting stuck.
+use std::{
+    str::FromStr,
+    sync::Mutex,
+    thread,
+    time::{Duration, Instant},
+};
+
+#[derive(Debug)]
+struct Bucket {
+    rate: Option<u64>,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl Bucket {
+    fn new(rate: Option<u64>) -> Self {
+        Bucket {
+            rate,
+            tokens: rate.unwrap_or(0) as f64,
+            last_refill: Instant::now(),
+        }
+    }
+
+    /// Takes `count` tokens and returns how long the caller has to wait to pay off the debt.
+    fn take(&mut self, count: u64) -> Duration {
+        let Some(rate) = self.rate.filter(|r| *r > 0) else {
+            return Duration::ZERO;
+        };
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate as f64).min(rate as f64) - count as f64;
+        self.last_refill = now;
+        if self.tokens >= 0.0 {
+            return Duration::ZERO;
+        }
+        Duration::from_secs_f64(-self.tokens / rate as f64)
+    }
+
+    fn set_rate(&mut self, rate: Option<u64>) {
+        *self = Bucket::new(rate);
+    }
+}
+
+/// Limits of bytes and files per second, `None` means unlimited.
+#[derive(Debug)]
+pub struct Throttle {
+    bytes: Mutex<Bucket>,
+    files: Mutex<Bucket>,
+}
+
+impl Throttle {
+    pub fn new(bytes_per_sec: Option<u64>, files_per_sec: Option<u64>) -> Self {
+        Throttle {
+            bytes: Mutex::new(Bucket::new(bytes_per_sec)),
+            files: Mutex::new(Bucket::new(files_per_sec)),
+        }
+    }
+
+    /// Accounts `count` transferred bytes and blocks as long as needed to keep the rate, returns the time waited.
+    pub fn bytes(&self, count: u64) -> Duration {
+        let wait = self.bytes.lock().unwrap().take(count);
+        thread::sleep(wait);
+        wait
+    }
+
+    /// Accounts one file about to be transferred and blocks as long as needed to keep the rate.
+    pub fn file(&self) -> Duration {
+        let wait = self.files.lock().unwrap().take(1);
+        thread::sleep(wait);
+        wait
+    }
+
+    pub fn set_bytes_per_sec(&self, rate: Option<u64>) {
+        self.bytes.lock().unwrap().set_rate(rate);
+    }
+
+    pub fn set_files_per_sec(&self, rate: Option<u64>) {
+        self.files.lock().unwrap().set_rate(rate);
+    }
+
+    /// Current limits of bytes and files per second.
+    pub fn limits(&self) -> (Option<u64>, Option<u64>) {
+        (
+            self.bytes.lock().unwrap().rate,
+            self.files.lock().unwrap().rate,
+        )
+    }
+}
+
+/// Represents a limit given by user - a number with optional `K`, `M` or `G` suffix (powers of 1024) or `off`. Zero
+/// is rejected rather than taken as unlimited or as stopping the transfer, `off` is what removes a limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limit(pub Option<u64>);
+
+impl FromStr for Limit {
+    fn from_str(limit: &str) -> Result<Self, Self::Err> {
+        if limit == "off" {
+            return Ok(Limit(None));
+        }
+        let (number, shift) = match limit.as_bytes().last() {
+            Some(b'K' | b'k') => (&limit[..limit.len() - 1], 10),
+            Some(b'M' | b'm') => (&limit[..limit.len() - 1], 20),
+            Some(b'G' | b'g') => (&limit[..limit.len() - 1], 30),
+            _ => (limit, 0),
+        };
+        match number.parse::<u64>().map(|n| n.checked_mul(1 << shift)) {
+            Ok(Some(0)) => Err(format!("limit `{limit}` is zero, use `off` for no limit")),
+            Ok(Some(n)) => Ok(Limit(Some(n))),
+            Ok(None) => Err(format!("limit `{limit}` is too large")),
+            Err(_) => Err(format!("invalid limit `{limit}`")),
+        }
+    }
+
+    type Err = String;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limits_are_parsed_and_zero_or_overflowing_ones_rejected() {
+        assert_eq!(Limit::from_str("off"), Ok(Limit(None)));
+        assert_eq!(Limit::from_str("512"), Ok(Limit(Some(512))));
+        assert_eq!(Limit::from_str("2M"), Ok(Limit(Some(2 << 20))));
+        assert!(Limit::from_str("0").is_err());
+        assert!(Limit::from_str("0K").is_err());
+        assert!(Limit::from_str("99999999999G").is_err());
+        assert!(Limit::from_str("fast").is_err());
+    }
+}
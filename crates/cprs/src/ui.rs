@@ -0,0 +1,206 @@
+//! Helpers for rendering the progress of a transfer in a terminal.
+use std::{
+    cmp::Reverse,
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+const DEFAULT_WIDTH: usize = 100;
+// Weight of the latest sample in the smoothed throughput, lower values react slower but jump around less.
+const SMOOTHING: f64 = 0.3;
+const SAMPLE_INTERVAL: Duration = Duration::from_millis(250);
+/// How many of the slowest and largest files are kept.
+const TOP_COUNT: usize = 3;
+
+/// Width of the terminal attached to stdout, falling back to `COLUMNS` and then to 100 columns.
+pub fn terminal_width() -> usize {
+    #[cfg(unix)]
+    {
+        // SAFETY: `winsize` is plain data for which zeroes are valid, the ioctl only writes into it.
+        let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+        // SAFETY: `TIOCGWINSZ` takes a pointer to a `winsize` which lives through the call.
+        if unsafe { libc::ioctl(libc::STDOUT_FILENO, libc::TIOCGWINSZ, &mut size) } == 0
+            && size.ws_col > 0
+        {
+            return size.ws_col as usize;
+        }
+    }
+    std::env::var("COLUMNS")
+        .ok()
+        .and_then(|c| c.parse().ok())
+        .filter(|c| *c > 0)
+        .unwrap_or(DEFAULT_WIDTH)
+}
+
+/// Progress bar of `fraction` which takes exactly `width` columns.
+pub fn bar(fraction: f64, width: usize) -> String {
+    let inner = width.saturating_sub(3);
+    let filled = ((fraction.clamp(0.0, 1.0) * inner as f64) as usize).min(inner);
+    format!("[{}>{}]", "=".repeat(filled), " ".repeat(inner - filled))
+}
+
+pub fn bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        return format!("{bytes} B");
+    }
+    format!("{value:.1} {}", UNITS[unit])
+}
+
+pub fn duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    match secs {
+        0..=59 => format!("{:.1}s", duration.as_secs_f64()),
+        60..=3599 => format!("{}m{:02}s", secs / 60, secs % 60),
+        _ => format!("{}h{:02}m{:02}s", secs / 3600, secs % 3600 / 60, secs % 60),
+    }
+}
+
+/// Cuts `line` to `width` characters.
+pub fn fit(line: &str, width: usize) -> String {
+    if line.chars().count() <= width {
+        return line.to_string();
+    }
+    let kept = line
+        .chars()
+        .take(width.saturating_sub(1))
+        .collect::<String>();
+    format!("{kept}…")
+}
+
+/// Cuts `path` to `width` characters, paths are more telling at their end so the start is dropped.
+pub fn fit_path(path: &str, width: usize) -> String {
+    let len = path.chars().count();
+    if len <= width {
+        return path.to_string();
+    }
+    let skip = len - width.saturating_sub(1);
+    format!("…{}", path.chars().skip(skip).collect::<String>())
+}
+
+/// Line of `prefix`, `path` and `suffix` where the path is cut so that the line fits in `width`.
+pub fn path_line(prefix: &str, path: &str, suffix: &str, width: usize) -> String {
+    let room = width.saturating_sub(prefix.chars().count() + suffix.chars().count());
+    format!("{prefix}{}{suffix}", fit_path(path, room))
+}
+
+/// Exponentially weighted moving average of bytes per second.
+#[derive(Debug)]
+pub struct Throughput {
+    rate: Option<f64>,
+    last_bytes: u64,
+    last_at: Instant,
+}
+
+impl Throughput {
+    pub fn new() -> Self {
+        Throughput {
+            rate: None,
+            last_bytes: 0,
+            last_at: Instant::now(),
+        }
+    }
+
+    /// Takes the total bytes done so far into the average once every sample interval.
+    pub fn sample(&mut self, done_bytes: u64) {
+        let elapsed = self.last_at.elapsed();
+        if elapsed < SAMPLE_INTERVAL {
+            return;
+        }
+        let rate = done_bytes.saturating_sub(self.last_bytes) as f64 / elapsed.as_secs_f64();
+        self.rate = Some(
+            self.rate
+                .map_or(rate, |r| SMOOTHING * rate + (1.0 - SMOOTHING) * r),
+        );
+        self.last_bytes = done_bytes;
+        self.last_at = Instant::now();
+    }
+
+    /// Bytes per second, `None` till the first sample.
+    pub fn rate(&self) -> Option<f64> {
+        self.rate
+    }
+}
+
+/// A file being transferred by one of the workers.
+#[derive(Debug)]
+pub struct InFlight {
+    pub started_at: Instant,
+    pub size: u64,
+    pub done: u64,
+}
+
+/// Files being transferred right now and the slowest and largest ones which are done.
+#[derive(Debug, Default)]
+pub struct Files {
+    pub in_flight: HashMap<String, InFlight>,
+    pub slowest: Vec<(String, Duration)>,
+    pub largest: Vec<(String, u64)>,
+}
+
+impl Files {
+    pub fn start(&mut self, path: &str, size: u64) {
+        self.in_flight.insert(
+            path.to_string(),
+            InFlight {
+                started_at: Instant::now(),
+                size,
+                done: 0,
+            },
+        );
+    }
+
+    pub fn progress(&mut self, path: &str, bytes: u64) {
+        if let Some(file) = self.in_flight.get_mut(path) {
+            file.done += bytes;
+        }
+    }
+
+    pub fn revert(&mut self, path: &str, bytes: u64) {
+        if let Some(file) = self.in_flight.get_mut(path) {
+            file.done = file.done.saturating_sub(bytes);
+        }
+    }
+
+    /// Removes the file from in flight ones, it is ranked among the slowest and largest only if it completed.
+    pub fn finish(&mut self, path: &str, completed: bool) {
+        let Some(file) = self.in_flight.remove(path) else {
+            return;
+        };
+        if !completed {
+            return;
+        }
+        self.slowest
+            .push((path.to_string(), file.started_at.elapsed()));
+        self.slowest.sort_by_key(|(_, took)| Reverse(*took));
+        self.slowest.truncate(TOP_COUNT);
+        self.largest.push((path.to_string(), file.size));
+        self.largest.sort_by_key(|(_, size)| Reverse(*size));
+        self.largest.truncate(TOP_COUNT);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn throughput_falls_while_nothing_is_done() {
+        let mut throughput = Throughput::new();
+        throughput.sample(1 << 20);
+        assert_eq!(throughput.rate(), None);
+        std::thread::sleep(SAMPLE_INTERVAL);
+        throughput.sample(1 << 20);
+        let rate = throughput.rate().unwrap();
+        assert!(rate > 0.0);
+        std::thread::sleep(SAMPLE_INTERVAL);
+        throughput.sample(1 << 20);
+        assert!(throughput.rate().unwrap() < rate);
+    }
+}
@@ -0,0 +1,180 @@
+//! Compares the work stealing `ThreadPool` with the single `Mutex<Receiver>` design it replaced, on the workload of
+//! `conc_adder` - one tiny job per number adding it to a shared counter.
+//!
+//! Jobs are either all submitted from the main thread (`flat`) or submitted by other jobs (`fan-out`), which is how
+//! cprs submits the children of each directory. The counter is an atomic so that the pools are what is measured.
+//! Pools are compared without timing jobs, as the channel pool does not either, the flat workload is also run with it
+//! to show what it costs.
+use std::{
+    sync::{
+        atomic::{AtomicI64, AtomicUsize, Ordering},
+        mpsc::{self, Sender},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle, Thread},
+    time::{Duration, Instant},
+};
+
+use threadpool::{Config, ThreadPool};
+
+const NUMBERS: i64 = 1 << 20;
+const FAN_OUT: i64 = 1 << 10;
+const RUNS: usize = 3;
+const POOL_SIZES: [usize; 5] = [1, 2, 4, 8, 16];
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// Pool as it was before work stealing, all workers take jobs from one channel behind a mutex.
+struct ChannelPool {
+    threads: Vec<JoinHandle<()>>,
+    sender: Option<Sender<Job>>,
+}
+
+impl ChannelPool {
+    fn new(cap: usize) -> Self {
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let threads = (0..cap)
+            .map(|_| {
+                let receiver = receiver.clone();
+                thread::spawn(move || loop {
+                    let job = receiver.lock().unwrap().recv();
+                    match job {
+                        Ok(job) => job(),
+                        Err(_) => return,
+                    }
+                })
+            })
+            .collect();
+        ChannelPool {
+            threads,
+            sender: Some(sender),
+        }
+    }
+}
+
+impl Drop for ChannelPool {
+    fn drop(&mut self) {
+        self.sender = None;
+        while let Some(thread) = self.threads.pop() {
+            thread.join().unwrap();
+        }
+    }
+}
+
+/// Counter the jobs add to, along with the number of jobs left and the thread to wake up when none are.
+struct Sum {
+    total: AtomicI64,
+    remaining: AtomicUsize,
+    waiter: Thread,
+}
+
+impl Sum {
+    fn new(jobs: i64) -> Arc<Self> {
+        Arc::new(Sum {
+            total: AtomicI64::new(0),
+            remaining: AtomicUsize::new(jobs as usize),
+            waiter: thread::current(),
+        })
+    }
+
+    fn add(&self, num: i64) {
+        self.total.fetch_add(num, Ordering::Relaxed);
+        if self.remaining.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.waiter.unpark();
+        }
+    }
+
+    fn wait(&self) -> i64 {
+        while self.remaining.load(Ordering::Acquire) > 0 {
+            thread::park();
+        }
+        self.total.load(Ordering::Relaxed)
+    }
+}
+
+/// Submits jobs to either of the pools, from the main thread or from other jobs.
+trait Submit: Send + Sync + 'static {
+    fn run(&self, job: Job);
+}
+
+impl Submit for Mutex<Sender<Job>> {
+    fn run(&self, job: Job) {
+        self.lock().unwrap().send(job).unwrap();
+    }
+}
+
+impl Submit for ThreadPool {
+    fn run(&self, job: Job) {
+        self.execute(job).unwrap();
+    }
+}
+
+fn flat(submit: &Arc<impl Submit>) -> i64 {
+    let sum = Sum::new(NUMBERS);
+    for num in 0..NUMBERS {
+        let sum = sum.clone();
+        submit.run(Box::new(move || sum.add(num)));
+    }
+    sum.wait()
+}
+
+fn fan_out(submit: &Arc<impl Submit>) -> i64 {
+    let sum = Sum::new(NUMBERS);
+    for start in (0..NUMBERS).step_by(FAN_OUT as usize) {
+        let (sum, inner) = (sum.clone(), submit.clone());
+        submit.run(Box::new(move || {
+            for num in start..start + FAN_OUT {
+                let sum = sum.clone();
+                inner.run(Box::new(move || sum.add(num)));
+            }
+        }));
+    }
+    sum.wait()
+}
+
+fn median(mut run: impl FnMut() -> i64) -> Duration {
+    let expected = (0..NUMBERS).sum::<i64>();
+    let mut times = (0..RUNS)
+        .map(|_| {
+            let start = Instant::now();
+            assert_eq!(run(), expected);
+            start.elapsed()
+        })
+        .collect::<Vec<Duration>>();
+    times.sort();
+    times[RUNS / 2]
+}
+
+fn main() {
+    println!("{NUMBERS} jobs, median of {RUNS} runs");
+    for size in POOL_SIZES {
+        // Channel pool is reached through a mutex as its sender can not be shared between jobs otherwise.
+        let channel_pool = ChannelPool::new(size);
+        let sender = Arc::new(Mutex::new(channel_pool.sender.clone().unwrap()));
+        let channel_flat = median(|| flat(&sender));
+        let channel_fan_out = median(|| fan_out(&sender));
+        drop(sender);
+        drop(channel_pool);
+
+        let pool = |timing| {
+            Arc::new(ThreadPool::with_config(Config {
+                core_threads: size,
+                max_threads: size,
+                timing,
+                ..Config::default()
+            }))
+        };
+        let untimed = pool(false);
+        let stealing_flat = median(|| flat(&untimed));
+        let stealing_fan_out = median(|| fan_out(&untimed));
+        drop(untimed);
+        let timed = pool(true);
+        let timed_flat = median(|| flat(&timed));
+
+        println!(
+            "{size:>2} threads - flat: channel {channel_flat:>10.2?} stealing {stealing_flat:>10.2?} \
+             timed {timed_flat:>10.2?}, fan-out: channel {channel_fan_out:>10.2?} stealing {stealing_fan_out:>10.2?}"
+        );
+    }
+}
@@ -0,0 +1,150 @@
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Condvar, Mutex},
+    task::{Context, Poll, Waker},
+    thread,
+    time::{Duration, Instant},
+};
+
+enum Outcome<R> {
+    Running,
+    /// Result of the job or the payload it panicked with.
+    Done(thread::Result<R>),
+    /// Job was dropped without being run.
+    Lost,
+    Taken,
+}
+
+struct Slot<R> {
+    outcome: Outcome<R>,
+    waker: Option<Waker>,
+}
+
+struct Shared<R> {
+    slot: Mutex<Slot<R>>,
+    finished: Condvar,
+}
+
+/// Handle to the result of a job submitted with `ThreadPool::submit`. It can be joined from a thread or awaited. Same as
+/// a `std::thread::JoinHandle`, the result is an error with the panic payload if the job panicked.
+pub struct JobHandle<R> {
+    shared: Arc<Shared<R>>,
+}
+
+/// Sending half of a `JobHandle`, kept along with the job. Dropping it without completing marks the job as lost so
+/// that its handle does not wait forever.
+pub(crate) struct Completion<R> {
+    shared: Option<Arc<Shared<R>>>,
+}
+
+pub(crate) fn channel<R>() -> (Completion<R>, JobHandle<R>) {
+    let shared = Arc::new(Shared {
+        slot: Mutex::new(Slot {
+            outcome: Outcome::Running,
+            waker: None,
+        }),
+        finished: Condvar::new(),
+    });
+    (
+        Completion {
+            shared: Some(shared.clone()),
+        },
+        JobHandle { shared },
+    )
+}
+
+impl<R> Completion<R> {
+    pub(crate) fn complete(mut self, result: thread::Result<R>) {
+        self.finish(Outcome::Done(result));
+    }
+
+    fn finish(&mut self, outcome: Outcome<R>) {
+        let Some(shared) = self.shared.take() else {
+            return;
+        };
+        let mut slot = shared.slot.lock().unwrap();
+        slot.outcome = outcome;
+        if let Some(waker) = slot.waker.take() {
+            waker.wake();
+        }
+        shared.finished.notify_all();
+    }
+}
+
+impl<R> Drop for Completion<R> {
+    fn drop(&mut self) {
+        self.finish(Outcome::Lost);
+    }
+}
+
+impl<R> Slot<R> {
+    /// Result of the job if it is done, panics if it was already taken.
+    fn take(&mut self) -> Option<thread::Result<R>> {
+        match std::mem::replace(&mut self.outcome, Outcome::Taken) {
+            Outcome::Running => {
+                self.outcome = Outcome::Running;
+                None
+            }
+            Outcome::Done(result) => Some(result),
+            Outcome::Lost => Some(Err(Box::new("job was dropped before it ran"))),
+            Outcome::Taken => panic!("result of the job was already taken"),
+        }
+    }
+}
+
+impl<R> JobHandle<R> {
+    pub fn is_finished(&self) -> bool {
+        !matches!(self.shared.slot.lock().unwrap().outcome, Outcome::Running)
+    }
+
+    /// Blocks till the job is done and returns its result.
+    pub fn join(self) -> thread::Result<R> {
+        let slot = self.shared.slot.lock().unwrap();
+        let mut slot = self
+            .shared
+            .finished
+            .wait_while(slot, |slot| matches!(slot.outcome, Outcome::Running))
+            .unwrap();
+        slot.take().unwrap()
+    }
+
+    /// Result of the job if it is done, without blocking.
+    pub fn try_join(&mut self) -> Option<thread::Result<R>> {
+        self.shared.slot.lock().unwrap().take()
+    }
+
+    /// Blocks till the job is done or `timeout` passes, whichever is first.
+    pub fn join_timeout(&mut self, timeout: Duration) -> Option<thread::Result<R>> {
+        let deadline = Instant::now() + timeout;
+        let mut slot = self.shared.slot.lock().unwrap();
+        while matches!(slot.outcome, Outcome::Running) {
+            let now = Instant::now();
+            if now >= deadline {
+                return None;
+            }
+            slot = self
+                .shared
+                .finished
+                .wait_timeout(slot, deadline - now)
+                .unwrap()
+                .0;
+        }
+        slot.take()
+    }
+}
+
+impl<R> Future for JobHandle<R> {
+    type Output = thread::Result<R>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut slot = self.shared.slot.lock().unwrap();
+        match slot.take() {
+            Some(result) => Poll::Ready(result),
+            None => {
+                slot.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
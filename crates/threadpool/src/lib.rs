@@ -1,4 +1,21 @@
+mod job;
 mod pool;
+mod queue;
+mod scope;
+mod stats;
+mod timer;
 
+pub use job::JobHandle;
+pub use pool::Config;
+pub use pool::QueuedJob;
+pub use pool::SubmitError;
 pub use pool::ThreadPool;
 pub use pool::ThreadPoolJobSender;
+pub use pool::WhenFull;
+pub use queue::Priority;
+pub use scope::Scope;
+pub use scope::ScopedJobHandle;
+pub use stats::Histogram;
+pub use stats::Stats;
+pub use stats::WorkerStats;
+pub use timer::ScheduledHandle;
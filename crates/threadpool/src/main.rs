@@ -1,12 +1,20 @@
 use threadpool::ThreadPool;
 
 fn main() {
-    let (p, job_q, result_q) = ThreadPool::new::<u64>(8);
+    let (pool, job_q, result_q) = ThreadPool::new::<u64>(8);
     for i in 0..200 {
-        job_q.add(Box::new(move || i * i));
+        job_q.add(Box::new(move || i * i)).unwrap();
     }
     drop(job_q);
+    // Jobs submitted directly have their own handles, which can be of any type.
+    let sum = pool
+        .submit(|| (0..200_u64).map(|i| i * i).sum::<u64>())
+        .unwrap();
+    let name = pool.submit(|| String::from("sum of squares")).unwrap();
     for res in result_q {
         println!("{res}");
     }
+    println!("{} - {}", name.join().unwrap(), sum.join().unwrap());
+    println!("{}", pool.stats());
+    pool.shutdown();
 }
@@ -1,56 +1,1093 @@
 use std::{
+    cell::RefCell,
+    fmt,
+    panic::{self, AssertUnwindSafe},
     sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
         mpsc::{channel, Receiver, Sender},
-        Arc, Mutex,
+        Arc, Condvar, Mutex, RwLock,
     },
     thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    job::{self, JobHandle},
+    queue::{Next, Priority, Queues, Task},
+    stats::{Stats, Totals, WorkerCounters},
+    timer::{Entry, Periodic, Repeat, ScheduledHandle, Timers},
 };
 
 type Job<T> = Box<dyn FnOnce() -> T + Send + 'static>;
 
+thread_local! {
+    /// Counters of the worker running on this thread along with the address of the state of its pool, so that jobs
+    /// run by it for other pools are not counted as its own.
+    static WORKER: RefCell<Option<(usize, Arc<WorkerCounters>)>> = const { RefCell::new(None) };
+}
+
+/// Error of submitting a job to a pool which can not take it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SubmitError {
+    /// Pool was shut down, jobs are no longer taken.
+    Shutdown,
+    /// Queue is full and the pool rejects jobs when it is.
+    Full,
+}
+
+impl fmt::Display for SubmitError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SubmitError::Shutdown => write!(f, "pool is shut down"),
+            SubmitError::Full => write!(f, "queue of the pool is full"),
+        }
+    }
+}
+
+impl std::error::Error for SubmitError {}
+
+/// What to do with a job submitted while the queue is full.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum WhenFull {
+    /// Submitter waits till there is space. Jobs submitted by jobs of the same pool are run right away instead, as
+    /// every worker waiting would leave nobody to make space.
+    #[default]
+    Block,
+    /// Job is not queued and `SubmitError::Full` is returned.
+    Reject,
+    /// Job is run on the thread submitting it.
+    CallerRuns,
+    /// Job of the lowest priority queued first is dropped to make space, its handle returns an error.
+    DropOldest,
+}
+
+/// Represents configuration of a `ThreadPool`.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Threads which are always kept, even when idle.
+    pub core_threads: usize,
+    /// Most threads the pool grows to when jobs queue up faster than they are done.
+    pub max_threads: usize,
+    /// Threads beyond `core_threads` exit after being idle this long.
+    pub keep_alive: Duration,
+    /// Most jobs which can be queued, unbounded if `None`.
+    pub max_queued: Option<usize>,
+    pub when_full: WhenFull,
+    /// Jobs of higher priority taken ahead of waiting jobs of a lower one before one of those is taken next.
+    pub aging: usize,
+    /// Threads of the pool are named after it, followed by the id of the worker or `timer`.
+    pub name: String,
+    /// Whether jobs are timed, for the histograms of `Stats` and the busy and idle time of workers. Reading the clock
+    /// three times per job only shows with tiny jobs.
+    pub timing: bool,
+}
+
+impl Default for Config {
+    /// As many threads as the machine can run in parallel all the time and an unbounded queue.
+    fn default() -> Self {
+        let threads = thread::available_parallelism().map_or(1, |threads| threads.get());
+        Config {
+            core_threads: threads,
+            max_threads: threads,
+            keep_alive: Duration::from_secs(60),
+            max_queued: None,
+            when_full: WhenFull::default(),
+            aging: 16,
+            name: String::from("threadpool"),
+            timing: true,
+        }
+    }
+}
+
+/// Job which was still queued when the pool was shut down with `shutdown_now` or timed out. Running it sends its
+/// result where it would have gone, dropping it makes its handle return an error.
+pub struct QueuedJob(Task);
+
+impl QueuedJob {
+    pub fn run(self) {
+        (self.0.job)()
+    }
+}
+
+/// State shared by the pool, its job senders and its workers.
+struct Shared {
+    queues: Queues,
+    max_queued: Option<usize>,
+    when_full: WhenFull,
+    /// No jobs are taken once closed, workers exit when there are none left.
+    closed: AtomicBool,
+    /// Held for reading while pushing a job and for writing while closing, so that no job gets in after workers may
+    /// have seen the pool closed.
+    submitting: RwLock<()>,
+    /// Workers which are alive or about to be spawned.
+    workers: AtomicUsize,
+    core_threads: AtomicUsize,
+    max_threads: AtomicUsize,
+    keep_alive: Duration,
+    /// Notified along with `exit` when a worker exits.
+    exited: Condvar,
+    exit: Mutex<()>,
+    /// Handles of every worker spawned, including the ones which replaced dead workers.
+    handles: Mutex<Vec<JoinHandle<()>>>,
+    next_worker_id: AtomicUsize,
+    panic_count: AtomicUsize,
+    timers: Arc<Timers>,
+    name: String,
+    timing: bool,
+    /// Counts of jobs run by other threads and of workers which exited, locked after `worker_counters`.
+    totals: Mutex<Totals>,
+    /// Counters of the workers alive.
+    worker_counters: Mutex<Vec<Arc<WorkerCounters>>>,
+    /// Thread queueing scheduled jobs once they are due, spawned when the first one is scheduled.
+    timer: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl Shared {
+    fn push(self: &Arc<Self>, mut task: Task, priority: Priority) -> Result<(), SubmitError> {
+        self.stamp(&mut task);
+        let pushed = self.push_bounded(task, priority);
+        if pushed.is_ok() {
+            self.grow_if_backed_up();
+        }
+        pushed
+    }
+
+    /// Queues a scheduled job which is due. It goes past the bound of the queue, as the timer thread can neither
+    /// block nor drop it.
+    fn push_due(self: &Arc<Self>, mut task: Task) {
+        self.stamp(&mut task);
+        {
+            let _submitting = self.submitting.read().unwrap();
+            if self.closed.load(Ordering::SeqCst) {
+                return;
+            }
+            self.queues.push(task, Priority::Normal);
+        }
+        self.grow_if_backed_up();
+    }
+
+    fn stamp(&self, task: &mut Task) {
+        if self.timing {
+            task.queued_at = Some(Instant::now());
+        }
+    }
+
+    fn grow_if_backed_up(self: &Arc<Self>) {
+        // Queue backs up when jobs wait while every worker is busy. Max is looked at first as it rarely changes, which
+        // keeps pools of a fixed size from reading counters every push changes.
+        let max_threads = self.max_threads.load(Ordering::Relaxed);
+        if self.workers.load(Ordering::Relaxed) < max_threads
+            && self.queues.idle() == 0
+            && self.queues.len() > 0
+        {
+            self.grow(max_threads);
+        }
+    }
+
+    fn schedule(
+        self: &Arc<Self>,
+        delay: Duration,
+        job: Periodic,
+        repeat: Repeat,
+    ) -> Result<ScheduledHandle, SubmitError> {
+        let handle = self
+            .timers
+            .schedule(delay, job, repeat)
+            .ok_or(SubmitError::Shutdown)?;
+        let mut timer = self.timer.lock().unwrap();
+        if timer.is_none() {
+            *timer = Some(spawn_timer(self));
+        }
+        Ok(handle)
+    }
+
+    /// Runs a scheduled job and puts it back if it is periodic, unless the pool was shut down meanwhile.
+    fn run_scheduled(&self, mut entry: Entry) {
+        match entry.run() {
+            Ok(true) => {
+                let _ = self.timers.insert(entry);
+            }
+            Ok(false) => {}
+            Err(payload) => {
+                self.panic_count.fetch_add(1, Ordering::Relaxed);
+                drop(payload);
+            }
+        }
+    }
+
+    fn push_bounded(&self, mut task: Task, priority: Priority) -> Result<(), SubmitError> {
+        loop {
+            let submitting = self.submitting.read().unwrap();
+            if self.closed.load(Ordering::SeqCst) {
+                return Err(SubmitError::Shutdown);
+            }
+            let Some(max_queued) = self.max_queued else {
+                self.queues.push(task, priority);
+                return Ok(());
+            };
+            task = match self.queues.try_push(task, priority, max_queued) {
+                Ok(()) => return Ok(()),
+                Err(task) => task,
+            };
+            match self.when_full {
+                WhenFull::Reject => return Err(SubmitError::Full),
+                WhenFull::Block if !self.queues.is_worker() => {
+                    // Closing waits for the guard, so it is not held while blocked. The pool may be closed meanwhile.
+                    drop(submitting);
+                    self.queues.wait_for_space(max_queued, &self.closed);
+                }
+                WhenFull::Block | WhenFull::CallerRuns => {
+                    // Task may submit more jobs, which would wait behind a pending shutdown if this was still held.
+                    drop(submitting);
+                    self.run(task);
+                    return Ok(());
+                }
+                WhenFull::DropOldest => drop(self.queues.pop_oldest()),
+            }
+        }
+    }
+
+    /// Runs a task, counting its panic. Jobs with handles catch their own panics, only the ones without get here.
+    /// It is counted as a job of the calling thread if that is a worker of the pool. Returns when it started and how
+    /// long it ran if jobs are timed.
+    fn run(&self, task: Task) -> Option<(Instant, Duration)> {
+        WORKER.with(|worker| {
+            let worker = worker.borrow();
+            let counters = worker
+                .as_ref()
+                .filter(|(id, _)| *id == self.id())
+                .map(|(_, counters)| counters);
+            let started = self.timing.then(Instant::now);
+            let waited = started
+                .zip(task.queued_at)
+                .map(|(started, queued_at)| started - queued_at);
+            match counters {
+                Some(counters) => counters.started(waited),
+                None => self.totals.lock().unwrap().started(waited),
+            }
+            let result = panic::catch_unwind(AssertUnwindSafe(task.job));
+            let ran = started.map(|started| started.elapsed());
+            // Counted before the payload is dropped, which may panic again.
+            match counters {
+                Some(counters) => counters.finished(ran),
+                None => self.totals.lock().unwrap().finished(ran),
+            }
+            if let Err(payload) = result {
+                self.panic_count.fetch_add(1, Ordering::Relaxed);
+                drop(payload);
+            }
+            started.zip(ran)
+        })
+    }
+
+    fn id(&self) -> usize {
+        self as *const Self as usize
+    }
+
+    fn stats(&self) -> Stats {
+        let workers = self.worker_counters.lock().unwrap();
+        let queued = self.queues.len();
+        let panicked = self.panic_count.load(Ordering::Relaxed);
+        self.totals
+            .lock()
+            .unwrap()
+            .stats(queued, panicked, &workers)
+    }
+
+    /// Spawns a worker if there are fewer than `limit`, returns whether it did.
+    fn grow(self: &Arc<Self>, limit: usize) -> bool {
+        let reserved = self
+            .workers
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |workers| {
+                (workers < limit).then_some(workers + 1)
+            });
+        if reserved.is_ok() {
+            spawn_worker(self);
+        }
+        reserved.is_ok()
+    }
+
+    /// Counts out the calling worker if there are more than `limit`, returns whether it did.
+    fn retire(&self, limit: usize) -> bool {
+        self.workers
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |workers| {
+                (workers > limit).then(|| workers - 1)
+            })
+            .is_ok()
+    }
+
+    fn close(&self) {
+        {
+            let _submitting = self.submitting.write().unwrap();
+            self.closed.store(true, Ordering::SeqCst);
+        }
+        self.queues.notify_all();
+        self.queues.notify_blocked();
+        self.timers.close();
+    }
+
+    fn drain(&self) -> Vec<QueuedJob> {
+        self.queues.drain().into_iter().map(QueuedJob).collect()
+    }
+
+    fn join_workers(&self) {
+        if let Some(timer) = self.timer.lock().unwrap().take() {
+            let _ = timer.join();
+        }
+        // Workers which die while being waited for push their replacements, so this goes on till none are left.
+        loop {
+            let Some(handle) = self.handles.lock().unwrap().pop() else {
+                break;
+            };
+            // Pool can be dropped by one of its own jobs, that worker exits by itself once the job is done.
+            if handle.thread().id() != thread::current().id() {
+                let _ = handle.join();
+            }
+        }
+    }
+}
+
 pub struct ThreadPool {
-    handles: Vec<JoinHandle<()>>,
+    shared: Arc<Shared>,
 }
 
 pub struct ThreadPoolJobSender<T> {
-    job_sender: Sender<Job<T>>,
+    shared: Arc<Shared>,
+    result_sender: Sender<T>,
 }
 
-impl<T> ThreadPoolJobSender<T> {
-    pub fn add(&self, job: Job<T>) {
-        self.job_sender.send(job).expect("job send error");
+impl<T: Send + 'static> ThreadPoolJobSender<T> {
+    /// Jobs of the pool waiting to be run, including the ones submitted by others.
+    pub fn queued(&self) -> usize {
+        self.shared.queues.len()
+    }
+
+    /// Queues a job whose result is sent to the receiver returned by `ThreadPool::new`. Jobs which panic send nothing.
+    pub fn add(&self, job: Job<T>) -> Result<(), SubmitError> {
+        self.add_with_priority(Priority::Normal, job)
+    }
+
+    /// Same as `add`, with the job taken ahead of or after others by its priority.
+    pub fn add_with_priority(&self, priority: Priority, job: Job<T>) -> Result<(), SubmitError> {
+        let result_sender = self.result_sender.clone();
+        let task = Task::new(Box::new(move || {
+            // Nobody listening for results is not an error of the job.
+            let _ = result_sender.send(job());
+        }));
+        self.shared.push(task, priority)
+    }
+}
+
+/// Lives on the stack of a worker and counts it out when it exits. It also spawns a replacement if the worker unwinds,
+/// which only happens when a panic escapes `catch_unwind`, like one from dropping the payload of another.
+struct Sentinel {
+    shared: Arc<Shared>,
+    id: usize,
+    counters: Arc<WorkerCounters>,
+    /// Worker was already counted out when it retired.
+    retired: bool,
+}
+
+impl Drop for Sentinel {
+    fn drop(&mut self) {
+        self.shared.queues.unregister(self.id);
+        {
+            let mut workers = self.shared.worker_counters.lock().unwrap();
+            workers.retain(|counters| !Arc::ptr_eq(counters, &self.counters));
+            self.shared.totals.lock().unwrap().retire(&self.counters);
+        }
+        if !self.retired {
+            if thread::panicking() {
+                // Replacement takes over the count of this worker. Counting it separately would have it see one
+                // worker too many and retire right away.
+                spawn_worker(&self.shared);
+            } else {
+                self.shared.workers.fetch_sub(1, Ordering::SeqCst);
+            }
+        }
+        let _exit = self.shared.exit.lock().unwrap();
+        self.shared.exited.notify_all();
     }
 }
 
+/// Spawns a worker which was already counted in `workers`.
+fn spawn_worker(shared: &Arc<Shared>) {
+    let id = shared.next_worker_id.fetch_add(1, Ordering::Relaxed);
+    let name = format!("{}-{id}", shared.name);
+    let counters = Arc::new(WorkerCounters::new(name.clone()));
+    shared
+        .worker_counters
+        .lock()
+        .unwrap()
+        .push(counters.clone());
+    let worker_shared = shared.clone();
+    let spawned = thread::Builder::new().name(name).spawn(move || {
+        worker_shared.queues.register(id);
+        WORKER.with(|worker| *worker.borrow_mut() = Some((worker_shared.id(), counters.clone())));
+        let mut sentinel = Sentinel {
+            shared: worker_shared,
+            id,
+            counters,
+            retired: false,
+        };
+        let shared = sentinel.shared.clone();
+        // Idle time is taken from when jobs start and end, which saves reading the clock around every wait.
+        let mut idle_since = Instant::now();
+        loop {
+            // Workers beyond the max exit right away after a resize, ones beyond the core once they are idle.
+            if shared.retire(shared.max_threads.load(Ordering::SeqCst)) {
+                sentinel.retired = true;
+                return;
+            }
+            let core = shared.core_threads.load(Ordering::SeqCst);
+            let idle_timeout =
+                (shared.workers.load(Ordering::SeqCst) > core).then_some(shared.keep_alive);
+            match shared.queues.next(&shared.closed, idle_timeout) {
+                Next::Task(task) => {
+                    if let Some((started, ran)) = shared.run(task) {
+                        sentinel.counters.idle(started - idle_since);
+                        sentinel.counters.busy(ran);
+                        idle_since = started + ran;
+                    }
+                }
+                Next::Closed => return,
+                Next::TimedOut if shared.retire(core) => {
+                    sentinel.retired = true;
+                    return;
+                }
+                Next::TimedOut if shared.timing => {
+                    let now = Instant::now();
+                    sentinel.counters.idle(now - idle_since);
+                    idle_since = now;
+                }
+                Next::TimedOut => {}
+            }
+        }
+    });
+    let handle = spawned.expect("unable to spawn worker thread");
+    let mut handles = shared.handles.lock().unwrap();
+    // Handles of retired workers are let go of, so that they do not pile up in a long running pool.
+    handles.retain(|handle| !handle.is_finished());
+    handles.push(handle);
+}
+
+fn spawn_timer(shared: &Arc<Shared>) -> JoinHandle<()> {
+    let name = format!("{}-timer", shared.name);
+    let shared = shared.clone();
+    thread::Builder::new()
+        .name(name)
+        .spawn(move || {
+            while let Some(entry) = shared.timers.next_due() {
+                let runner = shared.clone();
+                shared.push_due(Task::new(Box::new(move || runner.run_scheduled(entry))));
+            }
+        })
+        .expect("unable to spawn timer thread")
+}
+
 impl ThreadPool {
+    /// Pool of `cap` threads along with a sender of jobs returning `T` and the receiver of their results, which come
+    /// in the order the jobs are done. The receiver is closed once the sender is dropped and its jobs are done.
     pub fn new<T: Send + 'static>(cap: usize) -> (Self, ThreadPoolJobSender<T>, Receiver<T>) {
-        assert_ne!(cap, 0);
-        let (job_sender, job_receiver) = channel::<Job<T>>();
+        let pool = Self::with_capacity(cap);
+        let (job_sender, result_receiver) = pool.sender();
+        (pool, job_sender, result_receiver)
+    }
+
+    /// Pool of `cap` threads which takes jobs through `submit`.
+    pub fn with_capacity(cap: usize) -> Self {
+        Self::with_config(Config {
+            core_threads: cap,
+            max_threads: cap,
+            ..Config::default()
+        })
+    }
+
+    pub fn with_config(config: Config) -> Self {
+        assert!(config.core_threads <= config.max_threads && config.max_threads > 0);
+        assert_ne!(config.max_queued, Some(0));
+        assert!(config.aging > 0);
+        let shared = Arc::new(Shared {
+            queues: Queues::new(config.aging),
+            max_queued: config.max_queued,
+            when_full: config.when_full,
+            closed: AtomicBool::new(false),
+            submitting: RwLock::new(()),
+            workers: AtomicUsize::new(0),
+            core_threads: AtomicUsize::new(config.core_threads),
+            max_threads: AtomicUsize::new(config.max_threads),
+            keep_alive: config.keep_alive,
+            exited: Condvar::new(),
+            exit: Mutex::new(()),
+            handles: Mutex::new(Vec::with_capacity(config.core_threads)),
+            next_worker_id: AtomicUsize::new(0),
+            panic_count: AtomicUsize::new(0),
+            timers: Arc::new(Timers::new()),
+            timer: Mutex::new(None),
+            name: config.name,
+            timing: config.timing,
+            totals: Mutex::new(Totals::default()),
+            worker_counters: Mutex::new(Vec::with_capacity(config.core_threads)),
+        });
+        while shared.grow(config.core_threads) {}
+        ThreadPool { shared }
+    }
+
+    /// Another sender of jobs returning `T` along with the receiver of their results, closed once the sender is dropped
+    /// and its jobs are done.
+    pub fn sender<T: Send + 'static>(&self) -> (ThreadPoolJobSender<T>, Receiver<T>) {
         let (result_sender, result_receiver) = channel::<T>();
-        let job_receiver = Arc::new(Mutex::new(job_receiver));
-        let result_sender = Arc::new(Mutex::new(result_sender));
-        let mut handles = vec![];
-        for _i in 0..cap {
-            let job_receiver = Arc::clone(&job_receiver);
-            let result_sender = Arc::clone(&result_sender);
-            handles.push(thread::spawn(move || {
-                while let Ok(job) = job_receiver.lock().unwrap().recv() {
-                    result_sender.lock().unwrap().send(job()).unwrap();
-                }
-            }));
+        let job_sender = ThreadPoolJobSender {
+            shared: self.shared.clone(),
+            result_sender,
+        };
+        (job_sender, result_receiver)
+    }
+
+    /// Jobs waiting to be run.
+    pub fn queued(&self) -> usize {
+        self.shared.queues.len()
+    }
+
+    /// Threads alive, which may be in the middle of exiting.
+    pub fn threads(&self) -> usize {
+        self.shared.workers.load(Ordering::SeqCst)
+    }
+
+    /// Changes the core and max thread counts. Threads are spawned right away to make up the core, threads beyond
+    /// the max exit once they are done with their current job.
+    pub fn resize(&self, core_threads: usize, max_threads: usize) {
+        assert!(core_threads <= max_threads && max_threads > 0);
+        self.shared
+            .core_threads
+            .store(core_threads, Ordering::SeqCst);
+        self.shared.max_threads.store(max_threads, Ordering::SeqCst);
+        if !self.is_shutdown() {
+            while self.shared.grow(core_threads) {}
+        }
+        // Idle workers look at the new counts.
+        self.shared.queues.notify_all();
+    }
+
+    /// Queues a job without a handle, its panic is only counted.
+    pub fn execute<F>(&self, job: F) -> Result<(), SubmitError>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.execute_with_priority(Priority::Normal, job)
+    }
+
+    /// Same as `execute`, with the job taken ahead of or after others by its priority.
+    pub fn execute_with_priority<F>(&self, priority: Priority, job: F) -> Result<(), SubmitError>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.shared.push(Task::new(Box::new(job)), priority)
+    }
+
+    /// Queues a job and returns the handle to its result. A panic of the job is caught and returned by the handle.
+    pub fn submit<R, F>(&self, job: F) -> Result<JobHandle<R>, SubmitError>
+    where
+        F: FnOnce() -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        self.submit_with_priority(Priority::Normal, job)
+    }
+
+    /// Same as `submit`, with the job taken ahead of or after others by its priority.
+    pub fn submit_with_priority<R, F>(
+        &self,
+        priority: Priority,
+        job: F,
+    ) -> Result<JobHandle<R>, SubmitError>
+    where
+        F: FnOnce() -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        let (completion, handle) = job::channel();
+        let shared = self.shared.clone();
+        let task = Task::new(Box::new(move || {
+            let result = panic::catch_unwind(AssertUnwindSafe(job));
+            if result.is_err() {
+                shared.panic_count.fetch_add(1, Ordering::Relaxed);
+            }
+            completion.complete(result);
+        }));
+        self.shared.push(task, priority)?;
+        Ok(handle)
+    }
+
+    /// Queues `job` once `delay` has passed.
+    pub fn schedule<F>(&self, delay: Duration, job: F) -> Result<ScheduledHandle, SubmitError>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let mut job = Some(job);
+        let job = Box::new(move || {
+            if let Some(job) = job.take() {
+                job()
+            }
+        });
+        self.shared.schedule(delay, job, Repeat::Never)
+    }
+
+    /// Queues `job` after `initial_delay` and then every `period` from when the previous run was due. A run which
+    /// starts late does not make the next one earlier and runs missed meanwhile are skipped.
+    pub fn schedule_at_fixed_rate<F>(
+        &self,
+        initial_delay: Duration,
+        period: Duration,
+        job: F,
+    ) -> Result<ScheduledHandle, SubmitError>
+    where
+        F: FnMut() + Send + 'static,
+    {
+        assert!(!period.is_zero());
+        self.shared
+            .schedule(initial_delay, Box::new(job), Repeat::FixedRate(period))
+    }
+
+    /// Queues `job` after `initial_delay` and then `delay` after each run is done.
+    pub fn schedule_with_fixed_delay<F>(
+        &self,
+        initial_delay: Duration,
+        delay: Duration,
+        job: F,
+    ) -> Result<ScheduledHandle, SubmitError>
+    where
+        F: FnMut() + Send + 'static,
+    {
+        assert!(!delay.is_zero());
+        self.shared
+            .schedule(initial_delay, Box::new(job), Repeat::FixedDelay(delay))
+    }
+
+    /// Snapshot of what the pool is doing and has done.
+    pub fn stats(&self) -> Stats {
+        self.shared.stats()
+    }
+
+    /// Calls `report` with the stats of the pool every `period`, as a job of the pool which counts in them.
+    pub fn report_stats<F>(
+        &self,
+        period: Duration,
+        mut report: F,
+    ) -> Result<ScheduledHandle, SubmitError>
+    where
+        F: FnMut(Stats) + Send + 'static,
+    {
+        // Reporter is kept by the pool, it holding the pool as well would keep both alive.
+        let shared = Arc::downgrade(&self.shared);
+        self.schedule_at_fixed_rate(period, period, move || {
+            if let Some(shared) = shared.upgrade() {
+                report(shared.stats());
+            }
+        })
+    }
+
+    /// Number of jobs which panicked so far.
+    pub fn panic_count(&self) -> usize {
+        self.shared.panic_count.load(Ordering::Relaxed)
+    }
+
+    pub fn is_shutdown(&self) -> bool {
+        self.shared.closed.load(Ordering::SeqCst)
+    }
+
+    /// Queues a task of a scope, which catches its own panic.
+    pub(crate) fn push_task(&self, task: Task) -> Result<(), SubmitError> {
+        self.shared.push(task, Priority::Normal)
+    }
+
+    pub(crate) fn count_panic(&self) {
+        self.shared.panic_count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Whether the calling thread is a worker of this pool.
+    pub(crate) fn is_worker(&self) -> bool {
+        self.shared.queues.is_worker()
+    }
+
+    /// Runs one queued task on the calling thread, returns whether there was one.
+    pub(crate) fn help(&self) -> bool {
+        match self.shared.queues.find() {
+            Some(task) => {
+                self.shared.run(task);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Stops taking jobs, runs the ones already queued and waits for the workers to exit.
+    pub fn shutdown(&self) {
+        self.shared.close();
+        self.shared.join_workers();
+    }
+
+    /// Stops taking jobs and returns the ones still queued without running them. Jobs already running are finished,
+    /// this does not wait for them.
+    pub fn shutdown_now(&self) -> Vec<QueuedJob> {
+        self.shared.close();
+        self.shared.drain()
+    }
+
+    /// Same as `shutdown` while it takes less than `timeout`. After that the jobs still queued are returned the same as
+    /// `shutdown_now`, which may be none when only running jobs were left.
+    pub fn shutdown_timeout(&self, timeout: Duration) -> Result<(), Vec<QueuedJob>> {
+        self.shared.close();
+        let exit = self.shared.exit.lock().unwrap();
+        let (exit, _) = self
+            .shared
+            .exited
+            .wait_timeout_while(exit, timeout, |_| {
+                self.shared.workers.load(Ordering::SeqCst) > 0
+            })
+            .unwrap();
+        drop(exit);
+        if self.shared.workers.load(Ordering::SeqCst) > 0 {
+            return Err(self.shared.drain());
         }
-        (
-            ThreadPool { handles },
-            ThreadPoolJobSender { job_sender },
-            result_receiver,
-        )
+        self.shared.join_workers();
+        Ok(())
     }
 }
 
 impl Drop for ThreadPool {
+    /// Shuts the pool down, running the jobs already queued. Jobs added by job senders after that are rejected.
     fn drop(&mut self) {
-        while let Some(handle) = self.handles.pop() {
-            handle.join().unwrap();
+        self.shutdown();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        future::Future,
+        pin::pin,
+        sync::{mpsc, Arc, Barrier, BarrierWaitResult},
+        task::{Context, Poll, Wake, Waker},
+        thread::Thread,
+        time::Duration,
+    };
+
+    struct Unpark(Thread);
+
+    impl Wake for Unpark {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    fn block_on<F: Future>(future: F) -> F::Output {
+        let waker = Waker::from(Arc::new(Unpark(thread::current())));
+        let mut future = pin!(future);
+        loop {
+            match future.as_mut().poll(&mut Context::from_waker(&waker)) {
+                Poll::Ready(output) => return output,
+                Poll::Pending => thread::park(),
+            }
+        }
+    }
+
+    #[test]
+    fn handles_return_results_of_any_type() {
+        let pool = ThreadPool::with_capacity(2);
+        let square = pool.submit(|| 12_u64 * 12).unwrap();
+        let text = pool.submit(|| String::from("done")).unwrap();
+        assert_eq!(square.join().unwrap(), 144);
+        assert_eq!(block_on(text).unwrap(), "done");
+
+        let (gate_sender, gate) = mpsc::channel::<()>();
+        let mut gated = pool.submit(move || gate.recv().map(|_| 7)).unwrap();
+        assert!(gated.try_join().is_none());
+        assert!(gated.join_timeout(Duration::from_millis(20)).is_none());
+        gate_sender.send(()).unwrap();
+        let result = gated.join_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(result.unwrap(), Ok(7));
+    }
+
+    #[test]
+    fn jobs_submitted_by_jobs_are_run() {
+        let (pool, job_sender, results) = ThreadPool::new::<u64>(4);
+        let job_sender = Arc::new(job_sender);
+        for start in (0..1000).step_by(100) {
+            let inner = job_sender.clone();
+            job_sender
+                .add(Box::new(move || {
+                    // These go to the deque of the worker running this job, others steal from it.
+                    for i in start..start + 100 {
+                        inner.add(Box::new(move || i)).unwrap();
+                    }
+                    0
+                }))
+                .unwrap();
+        }
+        drop(job_sender);
+        assert_eq!(results.iter().sum::<u64>(), (0..1000).sum());
+        pool.shutdown();
+    }
+
+    /// Pool of one thread busy till the returned sender is used, with `max_queued` of 2 which are taken up.
+    fn full_pool(when_full: WhenFull) -> (ThreadPool, mpsc::Sender<()>, Vec<JobHandle<u32>>) {
+        let pool = ThreadPool::with_config(Config {
+            core_threads: 1,
+            max_threads: 1,
+            max_queued: Some(2),
+            when_full,
+            ..Config::default()
+        });
+        let (started_sender, started) = mpsc::channel();
+        let (gate_sender, gate) = mpsc::channel::<()>();
+        pool.execute(move || {
+            started_sender.send(()).unwrap();
+            gate.recv().unwrap();
+        })
+        .unwrap();
+        started.recv().unwrap();
+        let queued = (0..2).map(|i| pool.submit(move || i).unwrap()).collect();
+        assert_eq!(pool.queued(), 2);
+        (pool, gate_sender, queued)
+    }
+
+    #[test]
+    fn full_queue_policies() {
+        let (pool, gate, _) = full_pool(WhenFull::Reject);
+        assert_eq!(pool.submit(|| 2).err(), Some(SubmitError::Full));
+        gate.send(()).unwrap();
+
+        let (pool, gate, _) = full_pool(WhenFull::CallerRuns);
+        let caller = thread::current().id();
+        let ran_on = pool.submit(move || thread::current().id()).unwrap();
+        assert_eq!(ran_on.join().unwrap(), caller);
+        gate.send(()).unwrap();
+
+        let (pool, gate, queued) = full_pool(WhenFull::DropOldest);
+        let newest = pool.submit(|| 2).unwrap();
+        assert_eq!(pool.queued(), 2);
+        gate.send(()).unwrap();
+        let results = queued
+            .into_iter()
+            .chain([newest])
+            .map(|handle| handle.join().ok())
+            .collect::<Vec<Option<u32>>>();
+        assert_eq!(results, vec![None, Some(1), Some(2)]);
+
+        let (pool, gate, _) = full_pool(WhenFull::Block);
+        let pool = Arc::new(pool);
+        let submitter = {
+            let pool = pool.clone();
+            thread::spawn(move || pool.submit(|| 2).unwrap().join().unwrap())
+        };
+        thread::sleep(Duration::from_millis(20));
+        assert!(!submitter.is_finished());
+        gate.send(()).unwrap();
+        assert_eq!(submitter.join().unwrap(), 2);
+    }
+
+    #[test]
+    fn shutdown_is_not_held_up_by_a_submitter_blocked_on_a_full_queue() {
+        let (pool, gate, _) = full_pool(WhenFull::Block);
+        let pool = Arc::new(pool);
+        let (result_sender, result) = mpsc::channel();
+        {
+            let pool = pool.clone();
+            thread::spawn(move || result_sender.send(pool.submit(|| 2).err()).unwrap());
+        }
+        thread::sleep(Duration::from_millis(20));
+        let shutdown = {
+            let pool = pool.clone();
+            thread::spawn(move || pool.shutdown())
+        };
+        // Worker is still busy, the submitter sees the pool closed rather than space in the queue.
+        assert_eq!(
+            result.recv_timeout(Duration::from_secs(5)),
+            Ok(Some(SubmitError::Shutdown))
+        );
+        gate.send(()).unwrap();
+        shutdown.join().unwrap();
+    }
+
+    #[test]
+    fn stats_count_jobs_and_workers_are_named() {
+        let pool = ThreadPool::with_config(Config {
+            core_threads: 2,
+            max_threads: 2,
+            name: String::from("stats"),
+            ..Config::default()
+        });
+        let names = (0..10)
+            .map(|_| {
+                pool.submit(|| thread::current().name().unwrap().to_string())
+                    .unwrap()
+            })
+            .collect::<Vec<JobHandle<String>>>();
+        for name in names {
+            assert!(["stats-0", "stats-1"].contains(&name.join().unwrap().as_str()));
         }
+        assert!(pool.submit(|| panic!("boom")).unwrap().join().is_err());
+        let (reports_sender, reports) = mpsc::channel();
+        let reporter = pool
+            .report_stats(Duration::from_millis(5), move |stats| {
+                let _ = reports_sender.send(stats);
+            })
+            .unwrap();
+        // Reporter counts itself as running along with its earlier runs as completed. Counters of a job which just
+        // finished may still be on their way, so reports are taken till one adds up.
+        let stats = reports
+            .iter()
+            .find(|stats| {
+                let jobs = stats.workers.iter().map(|worker| worker.jobs).sum::<u64>();
+                stats.running == 1
+                    && stats.completed >= 11
+                    && jobs == stats.completed
+                    && stats.wait_time.count() == stats.completed + 1
+                    && stats.run_time.count() == stats.completed
+            })
+            .unwrap();
+        reporter.cancel();
+        assert_eq!(
+            (stats.queued, stats.panicked, stats.workers.len()),
+            (0, 1, 2)
+        );
+        assert!(stats.to_string().contains("stats-1 - "));
+    }
+
+    /// Order in which a pool of one thread runs two jobs of each priority, queued lowest first while it is busy.
+    fn run_order(aging: usize) -> Vec<Priority> {
+        let pool = ThreadPool::with_config(Config {
+            core_threads: 1,
+            max_threads: 1,
+            aging,
+            ..Config::default()
+        });
+        let (started_sender, started) = mpsc::channel();
+        let (gate_sender, gate) = mpsc::channel::<()>();
+        pool.execute(move || {
+            started_sender.send(()).unwrap();
+            gate.recv().unwrap();
+        })
+        .unwrap();
+        started.recv().unwrap();
+        let (order_sender, order) = mpsc::channel();
+        for priority in [Priority::Low, Priority::Normal, Priority::High] {
+            for _ in 0..2 {
+                let order_sender = order_sender.clone();
+                pool.execute_with_priority(priority, move || order_sender.send(priority).unwrap())
+                    .unwrap();
+            }
+        }
+        drop(order_sender);
+        gate_sender.send(()).unwrap();
+        order.iter().collect()
+    }
+
+    #[test]
+    fn jobs_are_taken_by_priority_with_aging() {
+        use Priority::*;
+        assert_eq!(run_order(16), [High, High, Normal, Normal, Low, Low]);
+        // Both lower levels age by two while the high jobs are taken, low goes first as it is checked first.
+        assert_eq!(run_order(2), [High, High, Low, Normal, Normal, Low]);
+    }
+
+    /// Waits a while for the pool to have `threads` threads.
+    fn wait_for_threads(pool: &ThreadPool, threads: usize) {
+        for _ in 0..500 {
+            if pool.threads() == threads {
+                return;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(pool.threads(), threads);
+    }
+
+    #[test]
+    fn pool_grows_to_max_and_shrinks_back_to_core() {
+        let pool = ThreadPool::with_config(Config {
+            core_threads: 1,
+            max_threads: 3,
+            keep_alive: Duration::from_millis(50),
+            ..Config::default()
+        });
+        assert_eq!(pool.threads(), 1);
+        // None of these is done till all 3 run at the same time.
+        let barrier = Arc::new(Barrier::new(3));
+        let handles = (0..3)
+            .map(|_| {
+                let barrier = barrier.clone();
+                pool.submit(move || barrier.wait()).unwrap()
+            })
+            .collect::<Vec<JobHandle<BarrierWaitResult>>>();
+        for mut handle in handles {
+            assert!(handle.join_timeout(Duration::from_secs(5)).is_some());
+        }
+        wait_for_threads(&pool, 1);
+
+        pool.resize(2, 4);
+        assert_eq!(pool.threads(), 2);
+        pool.resize(0, 1);
+        wait_for_threads(&pool, 1);
+        assert_eq!(pool.submit(|| 1).unwrap().join().unwrap(), 1);
+    }
+
+    /// Panics while dropping, so that the worker which drops it after catching the panic dies.
+    struct PanicOnDrop;
+
+    impl Drop for PanicOnDrop {
+        fn drop(&mut self) {
+            panic!("payload dropped");
+        }
+    }
+
+    #[test]
+    fn panics_are_returned_and_workers_replaced() {
+        let (pool, job_sender, results) = ThreadPool::new::<u32>(1);
+        let failed = pool.submit(|| -> u32 { panic!("boom") }).unwrap();
+        let payload = failed.join().unwrap_err();
+        assert_eq!(payload.downcast_ref::<&str>(), Some(&"boom"));
+
+        job_sender
+            .add(Box::new(|| panic::panic_any(PanicOnDrop)))
+            .unwrap();
+        job_sender.add(Box::new(|| 1)).unwrap();
+        drop(job_sender);
+        // The only worker died dropping the payload, the job after it is run by its replacement.
+        assert_eq!(results.recv_timeout(Duration::from_secs(5)), Ok(1));
+        assert_eq!(pool.submit(|| 2).unwrap().join().unwrap(), 2);
+        assert_eq!(pool.panic_count(), 2);
+    }
+
+    #[test]
+    fn shutdown_drains_or_returns_queued_jobs() {
+        let (pool, job_sender, results) = ThreadPool::new::<u32>(1);
+        for i in 0..4 {
+            job_sender.add(Box::new(move || i)).unwrap();
+        }
+        // Job sender is still alive, shutting down does not wait for it to be dropped.
+        pool.shutdown();
+        assert_eq!(results.try_iter().collect::<Vec<u32>>(), vec![0, 1, 2, 3]);
+        assert_eq!(job_sender.add(Box::new(|| 4)), Err(SubmitError::Shutdown));
+        assert!(pool.submit(|| ()).is_err());
+
+        let pool = ThreadPool::with_capacity(1);
+        let (gate_sender, gate) = mpsc::channel::<()>();
+        let running = pool.submit(move || gate.recv().is_ok()).unwrap();
+        let queued = (0..3)
+            .map(|i| pool.submit(move || i).unwrap())
+            .collect::<Vec<JobHandle<i32>>>();
+        let timed_out = pool.shutdown_timeout(Duration::from_millis(20));
+        let mut jobs = timed_out.err().unwrap();
+        assert_eq!(jobs.len(), 3);
+        gate_sender.send(()).unwrap();
+        assert!(running.join().unwrap());
+        jobs.remove(0).run();
+        drop(jobs);
+        // Only the job which was run has a result, order of the queued jobs is not kept across worker deques.
+        let results = queued
+            .into_iter()
+            .filter_map(|handle| handle.join().ok())
+            .collect::<Vec<i32>>();
+        assert_eq!(results.len(), 1);
     }
 }
@@ -0,0 +1,428 @@
+//! Work stealing queues of a pool.
+//!
+//! Every worker has its own deque, jobs submitted from outside of the pool go to a global injector and jobs submitted
+//! by a job go to the deque of the worker running it. Workers take from their own deque first, then a batch from the
+//! injector and then steal from other workers, so they only contend with each other when they run out of jobs.
+//!
+//! Jobs of other priorities than `Normal` go to an injector of their own. High ones are taken ahead of all others and
+//! low ones after all others. Each level with jobs waiting ages by one whenever a job of a higher level is taken ahead
+//! of them, and goes first once it is as old as the aging of the pool, so that low jobs do not starve.
+use std::{
+    cell::RefCell,
+    sync::{
+        atomic::{self, AtomicBool, AtomicUsize, Ordering},
+        Condvar, Mutex, RwLock,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use crossbeam_deque::{Injector, Steal, Stealer, Worker};
+
+/// Times a worker looks for a task before going to sleep.
+const SPINS: usize = 16;
+
+/// Levels of priority, `Priority` as an index.
+const LEVELS: usize = 3;
+const NORMAL: usize = Priority::Normal as usize;
+
+/// Priority of a job, which decides which of the queued jobs a worker takes next.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Priority {
+    High,
+    #[default]
+    Normal,
+    Low,
+}
+
+/// What a worker waiting for a task gets.
+pub(crate) enum Next {
+    Task(Task),
+    /// Queues are closed and there is nothing left.
+    Closed,
+    /// Nothing came within the idle timeout.
+    TimedOut,
+}
+
+/// Jobs are queued with their result already bound to where it goes, so one pool can run jobs of any return type.
+pub(crate) struct Task {
+    pub job: Box<dyn FnOnce() + Send + 'static>,
+    /// When the task was submitted if the pool times its jobs, for the time it waited.
+    pub queued_at: Option<Instant>,
+}
+
+impl Task {
+    pub fn new(job: Box<dyn FnOnce() + Send + 'static>) -> Self {
+        Task {
+            job,
+            queued_at: None,
+        }
+    }
+}
+
+thread_local! {
+    /// Deque of the worker running on this thread along with the address of the queues it belongs to, so that jobs
+    /// submitted to other pools from this thread are not taken as local.
+    static LOCAL: RefCell<Option<(usize, Worker<Task>)>> = const { RefCell::new(None) };
+}
+
+pub(crate) struct Queues {
+    /// Injector of each priority, deques of workers only hold normal tasks.
+    injectors: [Injector<Task>; LEVELS],
+    /// Tasks of each priority pushed and not yet taken.
+    waiting: [AtomicUsize; LEVELS],
+    /// Tasks of higher priorities taken while tasks of each priority were waiting, since one of those was taken.
+    ages: [AtomicUsize; LEVELS],
+    aging: usize,
+    /// Stealers of the deques of live workers along with their ids.
+    stealers: RwLock<Vec<(usize, Stealer<Task>)>>,
+    /// Workers which found nothing to run, spinning or asleep.
+    idle: AtomicUsize,
+    /// Workers waiting for a task which no one has woken up yet, only changed while holding `parked`.
+    sleepers: AtomicUsize,
+    /// Wakeups handed to sleepers which they have not taken yet.
+    parked: Mutex<usize>,
+    wake: Condvar,
+    /// Tasks pushed and not yet taken, counted before they are pushed so that a bound can not be overshot.
+    queued: AtomicUsize,
+    /// Submitters waiting for the queue to have space, only changed while holding `full`.
+    blocked: AtomicUsize,
+    full: Mutex<()>,
+    space: Condvar,
+}
+
+impl Queues {
+    pub fn new(aging: usize) -> Self {
+        Queues {
+            injectors: Default::default(),
+            waiting: Default::default(),
+            ages: Default::default(),
+            aging,
+            stealers: RwLock::new(vec![]),
+            sleepers: AtomicUsize::new(0),
+            idle: AtomicUsize::new(0),
+            parked: Mutex::new(0),
+            wake: Condvar::new(),
+            queued: AtomicUsize::new(0),
+            blocked: AtomicUsize::new(0),
+            full: Mutex::new(()),
+            space: Condvar::new(),
+        }
+    }
+
+    /// Tasks waiting to be run.
+    pub fn len(&self) -> usize {
+        self.queued.load(Ordering::SeqCst)
+    }
+
+    /// Workers which found nothing to run.
+    pub fn idle(&self) -> usize {
+        self.idle.load(Ordering::SeqCst)
+    }
+
+    /// Whether the calling thread is a worker of these queues.
+    pub fn is_worker(&self) -> bool {
+        LOCAL.with(|local| matches!(local.borrow().as_ref(), Some((id, _)) if *id == self.id()))
+    }
+
+    /// Pushes the task if fewer than `max` are queued, gives it back otherwise.
+    pub fn try_push(&self, task: Task, priority: Priority, max: usize) -> Result<(), Task> {
+        let reserved = self
+            .queued
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |queued| {
+                (queued < max).then_some(queued + 1)
+            });
+        match reserved {
+            Ok(_) => {
+                self.enqueue(task, priority);
+                Ok(())
+            }
+            Err(_) => Err(task),
+        }
+    }
+
+    /// Blocks till fewer than `max` tasks are queued or the pool is closed.
+    pub fn wait_for_space(&self, max: usize, closed: &AtomicBool) {
+        let mut full = self.full.lock().unwrap();
+        self.blocked.fetch_add(1, Ordering::SeqCst);
+        atomic::fence(Ordering::SeqCst);
+        while self.len() >= max && !closed.load(Ordering::SeqCst) {
+            full = self.space.wait(full).unwrap();
+        }
+        self.blocked.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    /// Counts out tasks of `level` which were taken and wakes up blocked submitters.
+    fn taken(&self, level: usize, count: usize) {
+        self.waiting[level].fetch_sub(count, Ordering::SeqCst);
+        self.queued.fetch_sub(count, Ordering::SeqCst);
+        // Pairs with the fence in `wait_for_space`, same as the sleepers.
+        atomic::fence(Ordering::SeqCst);
+        if self.blocked.load(Ordering::SeqCst) > 0 {
+            let _full = self.full.lock().unwrap();
+            self.space.notify_all();
+        }
+    }
+
+    /// Takes out the task of the lowest priority which was queued first, for making space by dropping it.
+    pub fn pop_oldest(&self) -> Option<Task> {
+        for level in (0..LEVELS).rev() {
+            loop {
+                let stolen = self.injectors[level].steal().or_else(|| {
+                    if level != NORMAL {
+                        return Steal::Empty;
+                    }
+                    let stealers = self.stealers.read().unwrap();
+                    stealers
+                        .iter()
+                        .map(|(_, stealer)| stealer.steal())
+                        .collect()
+                });
+                match stolen {
+                    Steal::Success(task) => {
+                        self.taken(level, 1);
+                        return Some(task);
+                    }
+                    Steal::Empty => break,
+                    Steal::Retry => continue,
+                }
+            }
+        }
+        None
+    }
+
+    fn id(&self) -> usize {
+        self as *const Self as usize
+    }
+
+    pub fn push(&self, task: Task, priority: Priority) {
+        self.queued.fetch_add(1, Ordering::SeqCst);
+        self.enqueue(task, priority);
+    }
+
+    fn enqueue(&self, task: Task, priority: Priority) {
+        let level = priority as usize;
+        self.waiting[level].fetch_add(1, Ordering::SeqCst);
+        let task = LOCAL.with(|local| match local.borrow().as_ref() {
+            Some((id, worker)) if *id == self.id() && level == NORMAL => {
+                worker.push(task);
+                None
+            }
+            _ => Some(task),
+        });
+        if let Some(task) = task {
+            self.injectors[level].push(task);
+        }
+        // Pairs with the fence in `next`, either the sleeper sees the task or this sees the sleeper.
+        atomic::fence(Ordering::SeqCst);
+        if self.sleepers.load(Ordering::SeqCst) > 0 {
+            self.wake(1);
+        }
+    }
+
+    /// Wakes up every sleeper, to see the pool closed or to look for tasks again.
+    pub fn notify_all(&self) {
+        self.wake(usize::MAX);
+    }
+
+    /// Wakes up every submitter blocked on a full queue, to see the pool closed.
+    pub fn notify_blocked(&self) {
+        let _full = self.full.lock().unwrap();
+        self.space.notify_all();
+    }
+
+    /// Hands wakeups to up to `count` sleepers. Woken sleepers stop counting right away, so that pushes made before
+    /// they get to run do not wake them again.
+    fn wake(&self, count: usize) {
+        let mut wakeups = self.parked.lock().unwrap();
+        let count = count.min(self.sleepers.load(Ordering::SeqCst));
+        if count == 0 {
+            return;
+        }
+        self.sleepers.fetch_sub(count, Ordering::SeqCst);
+        *wakeups += count;
+        if count == 1 {
+            self.wake.notify_one();
+        } else {
+            self.wake.notify_all();
+        }
+    }
+
+    /// Gives the calling thread its own deque, which other workers can steal from.
+    pub fn register(&self, worker_id: usize) {
+        let worker = Worker::new_fifo();
+        self.stealers
+            .write()
+            .unwrap()
+            .push((worker_id, worker.stealer()));
+        LOCAL.with(|local| *local.borrow_mut() = Some((self.id(), worker)));
+    }
+
+    /// Moves whatever is left in the deque of the calling thread to the injector and removes it.
+    pub fn unregister(&self, worker_id: usize) {
+        if let Some((_, worker)) = LOCAL.with(|local| local.borrow_mut().take()) {
+            while let Some(task) = worker.pop() {
+                self.injectors[NORMAL].push(task);
+            }
+        }
+        self.stealers
+            .write()
+            .unwrap()
+            .retain(|(id, _)| *id != worker_id);
+        self.notify_all();
+    }
+
+    /// Next task without waiting - the one of an aged level if there is one, then by priority.
+    pub fn find(&self) -> Option<Task> {
+        for level in (NORMAL..LEVELS).rev() {
+            if self.ages[level].load(Ordering::Relaxed) >= self.aging {
+                if let Some(task) = self.take(level) {
+                    return Some(task);
+                }
+            }
+        }
+        (0..LEVELS).find_map(|level| self.take(level))
+    }
+
+    /// Takes a task of `level` and ages the levels below which have tasks waiting.
+    fn take(&self, level: usize) -> Option<Task> {
+        if self.waiting[level].load(Ordering::SeqCst) == 0 {
+            return None;
+        }
+        let task = if level == NORMAL {
+            self.take_normal()
+        } else {
+            self.steal(&self.injectors[level])
+        }?;
+        self.taken(level, 1);
+        self.ages[level].store(0, Ordering::Relaxed);
+        for lower in level + 1..LEVELS {
+            if self.waiting[lower].load(Ordering::Relaxed) > 0 {
+                self.ages[lower].fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        Some(task)
+    }
+
+    fn steal(&self, injector: &Injector<Task>) -> Option<Task> {
+        loop {
+            match injector.steal() {
+                Steal::Success(task) => return Some(task),
+                Steal::Empty => return None,
+                Steal::Retry => continue,
+            }
+        }
+    }
+
+    /// Own deque, then a batch from the normal injector, then other workers.
+    fn take_normal(&self) -> Option<Task> {
+        let injector = &self.injectors[NORMAL];
+        LOCAL.with(|local| {
+            let local = local.borrow();
+            let worker = local
+                .as_ref()
+                .filter(|(id, _)| *id == self.id())
+                .map(|(_, worker)| worker);
+            if let Some(task) = worker.and_then(Worker::pop) {
+                return Some(task);
+            }
+            loop {
+                let stolen = match worker {
+                    Some(worker) => injector.steal_batch_and_pop(worker),
+                    None => injector.steal(),
+                }
+                .or_else(|| {
+                    let stealers = self.stealers.read().unwrap();
+                    stealers
+                        .iter()
+                        .map(|(_, stealer)| stealer.steal())
+                        .collect()
+                });
+                match stolen {
+                    Steal::Success(task) => return Some(task),
+                    Steal::Empty => return None,
+                    Steal::Retry => continue,
+                }
+            }
+        })
+    }
+
+    /// Next task to run, waiting for one till `closed` is set or for `idle_timeout` if there is one.
+    pub fn next(&self, closed: &AtomicBool, idle_timeout: Option<Duration>) -> Next {
+        if let Some(task) = self.find() {
+            return Next::Task(task);
+        }
+        self.idle.fetch_add(1, Ordering::SeqCst);
+        let next = self.wait(closed, idle_timeout);
+        self.idle.fetch_sub(1, Ordering::SeqCst);
+        next
+    }
+
+    fn wait(&self, closed: &AtomicBool, idle_timeout: Option<Duration>) -> Next {
+        let deadline = idle_timeout.map(|timeout| Instant::now() + timeout);
+        loop {
+            // Yielding a few times before going to sleep saves waking up again for every task when they come one by
+            // one, as the submitter gets to queue a few more meanwhile.
+            for _ in 0..SPINS {
+                if let Some(task) = self.find() {
+                    return Next::Task(task);
+                }
+                thread::yield_now();
+            }
+            let mut wakeups = self.parked.lock().unwrap();
+            self.sleepers.fetch_add(1, Ordering::SeqCst);
+            atomic::fence(Ordering::SeqCst);
+            // Nobody can hand this a wakeup before it waits, as that needs the lock.
+            if let Some(task) = self.find() {
+                self.sleepers.fetch_sub(1, Ordering::SeqCst);
+                return Next::Task(task);
+            }
+            if closed.load(Ordering::SeqCst) {
+                self.sleepers.fetch_sub(1, Ordering::SeqCst);
+                return Next::Closed;
+            }
+            while *wakeups == 0 {
+                let Some(deadline) = deadline else {
+                    wakeups = self.wake.wait(wakeups).unwrap();
+                    continue;
+                };
+                let now = Instant::now();
+                if now >= deadline {
+                    // Still holding the lock, so nobody handed this a wakeup meanwhile.
+                    self.sleepers.fetch_sub(1, Ordering::SeqCst);
+                    return Next::TimedOut;
+                }
+                wakeups = self.wake.wait_timeout(wakeups, deadline - now).unwrap().0;
+            }
+            *wakeups -= 1;
+        }
+    }
+
+    /// Takes every queued task out, from the deques of all workers and the injectors, highest priority first.
+    /// Deques go ahead of the normal injector as the tasks in them were taken from it earlier.
+    pub fn drain(&self) -> Vec<Task> {
+        let mut tasks = vec![];
+        for level in 0..LEVELS {
+            let drained = tasks.len();
+            loop {
+                let stolen = if level == NORMAL {
+                    let stealers = self.stealers.read().unwrap();
+                    stealers
+                        .iter()
+                        .map(|(_, stealer)| stealer.steal())
+                        .collect::<Steal<Task>>()
+                } else {
+                    Steal::Empty
+                }
+                .or_else(|| self.injectors[level].steal());
+                match stolen {
+                    Steal::Success(task) => tasks.push(task),
+                    Steal::Empty => break,
+                    Steal::Retry => continue,
+                }
+            }
+            self.taken(level, tasks.len() - drained);
+        }
+        tasks
+    }
+}
@@ -0,0 +1,260 @@
+use std::{
+    marker::PhantomData,
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Condvar, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+use crate::{
+    job::{self, JobHandle},
+    pool::{SubmitError, ThreadPool},
+    queue::Task,
+};
+
+/// How long a worker waiting for its scope sleeps when there is nothing to help with, as new tasks do not wake it.
+const HELP_INTERVAL: Duration = Duration::from_millis(1);
+
+/// Jobs of a scope which are not done yet, along with how many of the done ones panicked without being joined.
+struct ScopeData {
+    pending: Mutex<usize>,
+    done: Condvar,
+    unjoined_panics: AtomicUsize,
+}
+
+/// Holds a scoped job till it is dropped, either after being run or without it. Jobs are dropped before they are
+/// counted out, so whatever they borrow outlives them.
+struct Pending<F> {
+    job: Option<F>,
+    data: Arc<ScopeData>,
+}
+
+impl<F> Drop for Pending<F> {
+    fn drop(&mut self) {
+        drop(self.job.take());
+        let mut pending = self.data.pending.lock().unwrap();
+        *pending -= 1;
+        self.data.done.notify_all();
+    }
+}
+
+/// Scope of `ThreadPool::scope`, jobs spawned in it can borrow anything which outlives it.
+pub struct Scope<'scope, 'env: 'scope> {
+    pool: &'scope ThreadPool,
+    data: Arc<ScopeData>,
+    scope: PhantomData<&'scope mut &'scope ()>,
+    env: PhantomData<&'env mut &'env ()>,
+}
+
+/// Handle to the result of a scoped job, same as `JobHandle` apart from not outliving the scope.
+pub struct ScopedJobHandle<'scope, R> {
+    handle: JobHandle<R>,
+    data: Arc<ScopeData>,
+    /// Set if the job panicked, as opposed to it being dropped before it ran, which the scope does not count.
+    panicked: Arc<AtomicBool>,
+    scope: PhantomData<&'scope ()>,
+}
+
+impl<'scope, R> ScopedJobHandle<'scope, R> {
+    pub fn is_finished(&self) -> bool {
+        self.handle.is_finished()
+    }
+
+    /// Blocks till the job is done and returns its result. A panic returned here no longer makes the scope panic.
+    pub fn join(self) -> thread::Result<R> {
+        let result = self.handle.join();
+        if self.panicked.load(Ordering::SeqCst) {
+            self.data.unjoined_panics.fetch_sub(1, Ordering::SeqCst);
+        }
+        result
+    }
+}
+
+impl<'scope, 'env> Scope<'scope, 'env> {
+    /// Queues a job which may borrow from outside of the scope. It is done by the time the scope returns.
+    pub fn spawn<F, R>(&'scope self, job: F) -> Result<ScopedJobHandle<'scope, R>, SubmitError>
+    where
+        F: FnOnce() -> R + Send + 'scope,
+        R: Send + 'scope,
+    {
+        let (completion, handle) = job::channel();
+        *self.data.pending.lock().unwrap() += 1;
+        let mut pending = Pending {
+            job: Some(job),
+            data: self.data.clone(),
+        };
+        let pool = self.pool;
+        let panicked = Arc::new(AtomicBool::new(false));
+        let job_panicked = panicked.clone();
+        let task: Box<dyn FnOnce() + Send + 'scope> = Box::new(move || {
+            let job = pending.job.take().unwrap();
+            let result = panic::catch_unwind(AssertUnwindSafe(job));
+            if result.is_err() {
+                pool.count_panic();
+                job_panicked.store(true, Ordering::SeqCst);
+                pending.data.unjoined_panics.fetch_add(1, Ordering::SeqCst);
+            }
+            completion.complete(result);
+            drop(pending);
+        });
+        // SAFETY: The scope does not return till every task it spawned is dropped, whether it was run, rejected or
+        // drained from the pool, so nothing the task borrows goes away while it exists.
+        let task = unsafe {
+            std::mem::transmute::<
+                Box<dyn FnOnce() + Send + 'scope>,
+                Box<dyn FnOnce() + Send + 'static>,
+            >(task)
+        };
+        self.pool.push_task(Task::new(task))?;
+        Ok(ScopedJobHandle {
+            handle,
+            data: self.data.clone(),
+            panicked,
+            scope: PhantomData,
+        })
+    }
+}
+
+impl ThreadPool {
+    /// Runs `f` with a scope in which jobs borrowing from the stack of the caller can be spawned, same as
+    /// `std::thread::scope`. All of them are done by the time this returns. If any of them panicked without its handle
+    /// being joined, this panics after that.
+    ///
+    /// When called from a job of this pool, the worker runs other queued jobs while waiting instead of blocking.
+    pub fn scope<'env, F, T>(&self, f: F) -> T
+    where
+        F: for<'scope> FnOnce(&'scope Scope<'scope, 'env>) -> T,
+    {
+        let scope = Scope {
+            pool: self,
+            data: Arc::new(ScopeData {
+                pending: Mutex::new(0),
+                done: Condvar::new(),
+                unjoined_panics: AtomicUsize::new(0),
+            }),
+            scope: PhantomData,
+            env: PhantomData,
+        };
+        let result = panic::catch_unwind(AssertUnwindSafe(|| f(&scope)));
+        let mut pending = scope.data.pending.lock().unwrap();
+        while *pending > 0 {
+            if !self.is_worker() {
+                pending = scope.data.done.wait(pending).unwrap();
+                continue;
+            }
+            drop(pending);
+            let helped = self.help();
+            pending = scope.data.pending.lock().unwrap();
+            if !helped && *pending > 0 {
+                pending = scope
+                    .data
+                    .done
+                    .wait_timeout(pending, HELP_INTERVAL)
+                    .unwrap()
+                    .0;
+            }
+        }
+        drop(pending);
+        match result {
+            Err(payload) => panic::resume_unwind(payload),
+            Ok(_) if scope.data.unjoined_panics.load(Ordering::SeqCst) > 0 => {
+                panic!("a scoped job panicked")
+            }
+            Ok(result) => result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        panic::{self, AssertUnwindSafe},
+        sync::mpsc,
+    };
+
+    use crate::{Config, ThreadPool, WhenFull};
+
+    #[test]
+    fn scoped_jobs_borrow_and_are_joined() {
+        let pool = ThreadPool::with_capacity(1);
+        let mut nums = (1..=100).collect::<Vec<u64>>();
+        let total = pool.scope(|scope| {
+            let handles = nums
+                .chunks_mut(10)
+                .map(|chunk| {
+                    scope
+                        .spawn(move || {
+                            chunk.iter_mut().for_each(|num| *num *= 2);
+                            chunk.iter().sum::<u64>()
+                        })
+                        .unwrap()
+                })
+                .collect::<Vec<_>>();
+            handles
+                .into_iter()
+                .map(|handle| handle.join().unwrap())
+                .sum::<u64>()
+        });
+        assert_eq!(total, 10100);
+        assert_eq!(nums[99], 200);
+
+        // Scope opened by a job of a pool with a single thread, which has to run the inner jobs itself.
+        let nums = &nums;
+        let nested = pool.scope(|scope| {
+            let pool = &pool;
+            scope
+                .spawn(move || {
+                    let mut len = 0;
+                    pool.scope(|inner| {
+                        inner.spawn(|| len = nums.len()).unwrap();
+                    });
+                    len
+                })
+                .unwrap()
+                .join()
+                .unwrap()
+        });
+        assert_eq!(nested, 100);
+
+        let unjoined = panic::catch_unwind(AssertUnwindSafe(|| {
+            pool.scope(|scope| {
+                scope.spawn(|| panic!("boom")).unwrap();
+            })
+        }));
+        assert!(unjoined.is_err());
+        let joined = pool.scope(|scope| scope.spawn(|| panic!("boom")).unwrap().join());
+        assert!(joined.is_err());
+        assert_eq!(pool.panic_count(), 2);
+    }
+
+    #[test]
+    fn joining_a_dropped_scoped_job_is_not_a_panic() {
+        let pool = ThreadPool::with_config(Config {
+            core_threads: 1,
+            max_threads: 1,
+            max_queued: Some(1),
+            when_full: WhenFull::DropOldest,
+            ..Config::default()
+        });
+        let (started_sender, started) = mpsc::channel();
+        let (gate_sender, gate) = mpsc::channel::<()>();
+        pool.execute(move || {
+            started_sender.send(()).unwrap();
+            gate.recv().unwrap();
+        })
+        .unwrap();
+        started.recv().unwrap();
+        let newest = pool.scope(|scope| {
+            let dropped = scope.spawn(|| 1).unwrap();
+            let newest = scope.spawn(|| 2).unwrap();
+            assert!(dropped.join().is_err());
+            gate_sender.send(()).unwrap();
+            newest.join().unwrap()
+        });
+        assert_eq!(newest, 2);
+        assert_eq!(pool.panic_count(), 0);
+    }
+}
@@ -0,0 +1,286 @@
+//! Counters of a pool and the snapshots `ThreadPool::stats` gives of them.
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+/// Buckets of a histogram, bucket `i` holds durations under `2^i` microseconds and the last one everything longer.
+const BUCKETS: usize = 32;
+
+/// Durations of jobs bucketed by powers of two of microseconds.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Histogram {
+    counts: [u64; BUCKETS],
+}
+
+impl Histogram {
+    fn bucket(duration: Duration) -> usize {
+        let micros = duration.as_micros();
+        (u128::BITS - micros.leading_zeros()).min(BUCKETS as u32 - 1) as usize
+    }
+
+    fn bound(bucket: usize) -> Duration {
+        if bucket == BUCKETS - 1 {
+            Duration::MAX
+        } else {
+            Duration::from_micros(1 << bucket)
+        }
+    }
+
+    fn merge(&mut self, other: &Histogram) {
+        for (count, other) in self.counts.iter_mut().zip(other.counts) {
+            *count += other;
+        }
+    }
+
+    /// Durations recorded.
+    pub fn count(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// Count of each bucket along with the duration all of its durations are under.
+    pub fn buckets(&self) -> impl Iterator<Item = (Duration, u64)> + '_ {
+        self.counts
+            .iter()
+            .enumerate()
+            .map(|(bucket, count)| (Self::bound(bucket), *count))
+    }
+
+    /// Duration which at least `fraction` of the durations are under, rounded up to the bound of its bucket. Zero
+    /// when nothing was recorded.
+    pub fn percentile(&self, fraction: f64) -> Duration {
+        let rank = (self.count() as f64 * fraction.clamp(0.0, 1.0)).ceil() as u64;
+        let mut seen = 0;
+        for (bound, count) in self.buckets() {
+            seen += count;
+            if seen >= rank.max(1) {
+                return bound;
+            }
+        }
+        Duration::ZERO
+    }
+}
+
+impl fmt::Display for Histogram {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "p50 <{:?} p90 <{:?} p99 <{:?}",
+            self.percentile(0.5),
+            self.percentile(0.9),
+            self.percentile(0.99)
+        )
+    }
+}
+
+/// Adds to a counter which only the thread owning it writes to. Cheaper than `fetch_add`, as nothing else has to be
+/// kept out of the cache line meanwhile.
+fn add(counter: &AtomicU64, value: u64) {
+    counter.store(counter.load(Ordering::Relaxed) + value, Ordering::Relaxed);
+}
+
+/// Histogram recorded into by one thread and read by any.
+#[derive(Default)]
+struct AtomicHistogram {
+    counts: [AtomicU64; BUCKETS],
+}
+
+impl AtomicHistogram {
+    fn record(&self, duration: Duration) {
+        add(&self.counts[Histogram::bucket(duration)], 1);
+    }
+
+    fn snapshot(&self) -> Histogram {
+        Histogram {
+            counts: self
+                .counts
+                .each_ref()
+                .map(|count| count.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+/// Counters of one worker, written to only by its thread.
+pub(crate) struct WorkerCounters {
+    name: String,
+    /// Jobs running, more than one when a job runs others while waiting.
+    running: AtomicU64,
+    jobs: AtomicU64,
+    busy: AtomicU64,
+    idle: AtomicU64,
+    wait_time: AtomicHistogram,
+    run_time: AtomicHistogram,
+}
+
+impl WorkerCounters {
+    pub fn new(name: String) -> Self {
+        WorkerCounters {
+            name,
+            running: AtomicU64::new(0),
+            jobs: AtomicU64::new(0),
+            busy: AtomicU64::new(0),
+            idle: AtomicU64::new(0),
+            wait_time: AtomicHistogram::default(),
+            run_time: AtomicHistogram::default(),
+        }
+    }
+
+    /// Counts a job in as running, along with how long it waited if jobs are timed.
+    pub fn started(&self, waited: Option<Duration>) {
+        add(&self.running, 1);
+        if let Some(waited) = waited {
+            self.wait_time.record(waited);
+        }
+    }
+
+    pub fn finished(&self, ran: Option<Duration>) {
+        if let Some(ran) = ran {
+            self.run_time.record(ran);
+        }
+        add(&self.jobs, 1);
+        self.running
+            .store(self.running.load(Ordering::Relaxed) - 1, Ordering::Relaxed);
+    }
+
+    /// Time spent on jobs taken from the queue, which includes the ones those ran themselves.
+    pub fn busy(&self, ran: Duration) {
+        add(&self.busy, ran.as_nanos() as u64);
+    }
+
+    pub fn idle(&self, waited: Duration) {
+        add(&self.idle, waited.as_nanos() as u64);
+    }
+
+    fn snapshot(&self) -> WorkerStats {
+        WorkerStats {
+            name: self.name.clone(),
+            jobs: self.jobs.load(Ordering::Relaxed),
+            busy: Duration::from_nanos(self.busy.load(Ordering::Relaxed)),
+            idle: Duration::from_nanos(self.idle.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+/// Counts of jobs run by threads which are not workers of the pool, along with the ones of workers which exited.
+#[derive(Default)]
+pub(crate) struct Totals {
+    running: u64,
+    completed: u64,
+    wait_time: Histogram,
+    run_time: Histogram,
+}
+
+impl Totals {
+    pub fn started(&mut self, waited: Option<Duration>) {
+        self.running += 1;
+        if let Some(waited) = waited {
+            self.wait_time.counts[Histogram::bucket(waited)] += 1;
+        }
+    }
+
+    pub fn finished(&mut self, ran: Option<Duration>) {
+        if let Some(ran) = ran {
+            self.run_time.counts[Histogram::bucket(ran)] += 1;
+        }
+        self.completed += 1;
+        self.running -= 1;
+    }
+
+    /// Takes over the counts of a worker which exited.
+    pub fn retire(&mut self, worker: &WorkerCounters) {
+        self.completed += worker.jobs.load(Ordering::Relaxed);
+        self.wait_time.merge(&worker.wait_time.snapshot());
+        self.run_time.merge(&worker.run_time.snapshot());
+    }
+
+    /// Stats of a pool with these totals and `workers` alive.
+    pub fn stats(&self, queued: usize, panicked: usize, workers: &[Arc<WorkerCounters>]) -> Stats {
+        let mut stats = Stats {
+            queued,
+            running: self.running as usize,
+            completed: self.completed,
+            panicked,
+            workers: Vec::with_capacity(workers.len()),
+            wait_time: self.wait_time.clone(),
+            run_time: self.run_time.clone(),
+        };
+        for worker in workers {
+            stats.running += worker.running.load(Ordering::Relaxed) as usize;
+            stats.completed += worker.jobs.load(Ordering::Relaxed);
+            stats.wait_time.merge(&worker.wait_time.snapshot());
+            stats.run_time.merge(&worker.run_time.snapshot());
+            stats.workers.push(worker.snapshot());
+        }
+        stats
+    }
+}
+
+/// What a worker did since it was spawned. Busy time is added once a job is done and idle time once the next one
+/// starts, both stay zero if the pool does not time its jobs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WorkerStats {
+    /// Name of the thread, the name of the pool followed by the id of the worker.
+    pub name: String,
+    pub jobs: u64,
+    pub busy: Duration,
+    pub idle: Duration,
+}
+
+/// Snapshot of what a pool is doing, from `ThreadPool::stats`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Stats {
+    pub queued: usize,
+    pub running: usize,
+    /// Jobs done, including the ones which panicked and the ones run by submitters or waiting scopes.
+    pub completed: u64,
+    pub panicked: usize,
+    /// Workers alive, retired ones are gone along with their counts.
+    pub workers: Vec<WorkerStats>,
+    /// Time from submitting each job to it starting, empty along with `run_time` if the pool does not time its jobs.
+    pub wait_time: Histogram,
+    pub run_time: Histogram,
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} queued, {} running, {} completed, {} panicked, wait {}, run {}",
+            self.queued, self.running, self.completed, self.panicked, self.wait_time, self.run_time
+        )?;
+        for worker in &self.workers {
+            write!(
+                f,
+                "\n{} - {} jobs, busy {:?}, idle {:?}",
+                worker.name, worker.jobs, worker.busy, worker.idle
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn histogram_buckets_and_percentiles() {
+        let histogram = AtomicHistogram::default();
+        assert_eq!(histogram.snapshot().percentile(0.5), Duration::ZERO);
+        for micros in [0, 1, 3, 3, 100, 1_000_000] {
+            histogram.record(Duration::from_micros(micros));
+        }
+        histogram.record(Duration::MAX);
+        let histogram = histogram.snapshot();
+        assert_eq!(histogram.count(), 7);
+        assert_eq!(histogram.percentile(0.0), Duration::from_micros(1));
+        assert_eq!(histogram.percentile(0.5), Duration::from_micros(4));
+        assert_eq!(histogram.percentile(0.7), Duration::from_micros(128));
+        assert_eq!(histogram.percentile(1.0), Duration::MAX);
+        assert_eq!(histogram.buckets().count(), BUCKETS);
+    }
+}
@@ -0,0 +1,305 @@
+//! Delayed and periodic jobs of a pool.
+//!
+//! Scheduled jobs wait in a heap ordered by when they are due. A single timer thread sleeps till the earliest one is
+//! due and queues it on the pool like any other job. Periodic jobs are put back into the heap once a run is done, so
+//! runs of the same job never overlap.
+use std::{
+    cmp::Ordering as CmpOrdering,
+    collections::BinaryHeap,
+    mem,
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Condvar, Mutex, Weak,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+pub(crate) type Periodic = Box<dyn FnMut() + Send + 'static>;
+
+/// When a job runs again after a run.
+pub(crate) enum Repeat {
+    Never,
+    /// Runs are `period` apart from when the previous one was due.
+    FixedRate(Duration),
+    /// Runs are `delay` apart from when the previous one was done.
+    FixedDelay(Duration),
+}
+
+/// State of a scheduled job shared with its handle.
+struct State {
+    cancelled: AtomicBool,
+    done: AtomicBool,
+    runs: AtomicUsize,
+}
+
+pub(crate) struct Entry {
+    due: Instant,
+    /// Order of insertion, for jobs due at the same time to run in the order they were scheduled.
+    seq: u64,
+    job: Periodic,
+    repeat: Repeat,
+    state: Arc<State>,
+}
+
+impl Entry {
+    /// Runs the job and works out when it is due next, returns whether it is. A job which panics is not run again.
+    pub fn run(&mut self) -> thread::Result<bool> {
+        if self.state.cancelled.load(Ordering::SeqCst) {
+            return Ok(false);
+        }
+        panic::catch_unwind(AssertUnwindSafe(&mut self.job))?;
+        self.state.runs.fetch_add(1, Ordering::SeqCst);
+        self.due = match self.repeat {
+            Repeat::Never => return Ok(false),
+            // Runs which were missed while the job was late are skipped rather than made up for.
+            Repeat::FixedRate(period) => (self.due + period).max(Instant::now()),
+            Repeat::FixedDelay(delay) => Instant::now() + delay,
+        };
+        Ok(!self.state.cancelled.load(Ordering::SeqCst))
+    }
+}
+
+impl Drop for Entry {
+    fn drop(&mut self) {
+        self.state.done.store(true, Ordering::SeqCst);
+    }
+}
+
+impl PartialEq for Entry {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == CmpOrdering::Equal
+    }
+}
+
+impl Eq for Entry {}
+
+impl PartialOrd for Entry {
+    fn partial_cmp(&self, other: &Self) -> Option<CmpOrdering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Entry {
+    /// Reversed, as the heap gives the greatest first and the earliest is wanted.
+    fn cmp(&self, other: &Self) -> CmpOrdering {
+        (other.due, other.seq).cmp(&(self.due, self.seq))
+    }
+}
+
+struct Heap {
+    entries: BinaryHeap<Entry>,
+    next_seq: u64,
+    closed: bool,
+}
+
+pub(crate) struct Timers {
+    heap: Mutex<Heap>,
+    /// Notified when an entry is inserted or removed, or the timers are closed.
+    changed: Condvar,
+}
+
+/// Handle of a scheduled job, which can cancel the runs it has left.
+pub struct ScheduledHandle {
+    state: Arc<State>,
+    timers: Weak<Timers>,
+}
+
+impl ScheduledHandle {
+    /// Stops the job from running again, a run which already started is finished. Returns whether there were runs
+    /// left to cancel.
+    pub fn cancel(&self) -> bool {
+        if self.state.cancelled.swap(true, Ordering::SeqCst)
+            || self.state.done.load(Ordering::SeqCst)
+        {
+            return false;
+        }
+        if let Some(timers) = self.timers.upgrade() {
+            timers.remove(&self.state);
+        }
+        true
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.state.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Whether the job will not run again, because it was cancelled, it was not periodic and ran, it panicked or the
+    /// pool was shut down.
+    pub fn is_done(&self) -> bool {
+        self.state.done.load(Ordering::SeqCst)
+    }
+
+    /// Number of times the job ran to completion.
+    pub fn runs(&self) -> usize {
+        self.state.runs.load(Ordering::SeqCst)
+    }
+}
+
+impl Timers {
+    pub fn new() -> Self {
+        Timers {
+            heap: Mutex::new(Heap {
+                entries: BinaryHeap::new(),
+                next_seq: 0,
+                closed: false,
+            }),
+            changed: Condvar::new(),
+        }
+    }
+
+    /// Adds a job due after `delay`, returns `None` if the timers are closed.
+    pub fn schedule(
+        self: &Arc<Self>,
+        delay: Duration,
+        job: Periodic,
+        repeat: Repeat,
+    ) -> Option<ScheduledHandle> {
+        let state = Arc::new(State {
+            cancelled: AtomicBool::new(false),
+            done: AtomicBool::new(false),
+            runs: AtomicUsize::new(0),
+        });
+        let entry = Entry {
+            due: Instant::now() + delay,
+            seq: 0,
+            job,
+            repeat,
+            state: state.clone(),
+        };
+        self.insert(entry).ok()?;
+        Some(ScheduledHandle {
+            state,
+            timers: Arc::downgrade(self),
+        })
+    }
+
+    /// Puts an entry into the heap, gives it back if the timers are closed.
+    pub fn insert(&self, mut entry: Entry) -> Result<(), Entry> {
+        let mut heap = self.heap.lock().unwrap();
+        if heap.closed {
+            return Err(entry);
+        }
+        entry.seq = heap.next_seq;
+        heap.next_seq += 1;
+        heap.entries.push(entry);
+        self.changed.notify_one();
+        Ok(())
+    }
+
+    fn remove(&self, state: &Arc<State>) {
+        let mut heap = self.heap.lock().unwrap();
+        let (removed, kept): (Vec<Entry>, Vec<Entry>) = mem::take(&mut heap.entries)
+            .into_vec()
+            .into_iter()
+            .partition(|entry| Arc::ptr_eq(&entry.state, state));
+        heap.entries = kept.into();
+        self.changed.notify_one();
+        // Job may own anything, including what schedules other jobs when dropped.
+        drop(heap);
+        drop(removed);
+    }
+
+    /// Waits for the earliest entry to be due and takes it out, returns `None` once the timers are closed.
+    pub fn next_due(&self) -> Option<Entry> {
+        let mut heap = self.heap.lock().unwrap();
+        loop {
+            if heap.closed {
+                return None;
+            }
+            let now = Instant::now();
+            heap = match heap.entries.peek() {
+                None => self.changed.wait(heap).unwrap(),
+                Some(entry) if entry.due <= now => return heap.entries.pop(),
+                Some(entry) => {
+                    let timeout = entry.due - now;
+                    self.changed.wait_timeout(heap, timeout).unwrap().0
+                }
+            };
+        }
+    }
+
+    /// Drops every entry and stops taking new ones.
+    pub fn close(&self) {
+        let mut heap = self.heap.lock().unwrap();
+        heap.closed = true;
+        let entries = mem::take(&mut heap.entries);
+        self.changed.notify_all();
+        drop(heap);
+        drop(entries);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::mpsc,
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use crate::{SubmitError, ThreadPool};
+
+    #[test]
+    fn scheduled_jobs_run_when_due_and_can_be_cancelled() {
+        let pool = ThreadPool::with_capacity(2);
+        let (sender, receiver) = mpsc::channel();
+        let start = Instant::now();
+        let delayed = sender.clone();
+        let once = pool
+            .schedule(Duration::from_millis(50), move || {
+                delayed.send(start.elapsed()).unwrap()
+            })
+            .unwrap();
+        let cancelled = sender.clone();
+        let never = pool
+            .schedule(Duration::from_millis(20), move || {
+                cancelled.send(Duration::ZERO).unwrap()
+            })
+            .unwrap();
+        assert!(never.cancel());
+        assert!(receiver.recv().unwrap() >= Duration::from_millis(50));
+        // Run is counted once the job returns, after it sent.
+        while !once.is_done() {
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert!(once.runs() == 1 && !once.cancel());
+        assert!(never.is_done() && never.runs() == 0);
+
+        for periodic in [
+            pool.schedule_at_fixed_rate(Duration::ZERO, Duration::from_millis(5), || {})
+                .unwrap(),
+            pool.schedule_with_fixed_delay(Duration::ZERO, Duration::from_millis(5), || {})
+                .unwrap(),
+        ] {
+            while periodic.runs() < 3 {
+                thread::sleep(Duration::from_millis(5));
+            }
+            assert!(periodic.cancel());
+            thread::sleep(Duration::from_millis(20));
+            let runs = periodic.runs();
+            assert!(periodic.is_done());
+            thread::sleep(Duration::from_millis(20));
+            assert_eq!(periodic.runs(), runs);
+        }
+
+        let panicking = pool
+            .schedule_with_fixed_delay(Duration::ZERO, Duration::from_millis(1), || panic!("boom"))
+            .unwrap();
+        while !panicking.is_done() {
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert_eq!(panicking.runs(), 0);
+        assert_eq!(pool.panic_count(), 1);
+
+        let pending = pool.schedule(Duration::from_secs(60), || {}).unwrap();
+        pool.shutdown();
+        assert!(pending.is_done() && pending.runs() == 0);
+        assert!(matches!(
+            pool.schedule(Duration::ZERO, || {}),
+            Err(SubmitError::Shutdown)
+        ));
+        assert!(receiver.try_recv().is_err());
+    }
+}
@@ -15,6 +15,7 @@ pub fn build_from_args(mut args: impl Iterator<Item = String>) -> Config {
         depth_check: None,
         exclude_pattern: None,
         build_method: BuildMethod::SerialAsync,
+        follow_symlinks: false,
     };
     loop {
         let item = args.next();
@@ -3,7 +3,7 @@
 A program to get the tree structure information of given directory in a recursive manner. This is same as [Windows `tree` command](https://learn.microsoft.com/en-us/windows-server/administration/windows-commands/tree) hence the name `win_tree` except that this supports json formating, depth control and filtering of files based on pattern matching on names.
 
 # Performance
-- **[No support for Symlinks]** Program uses `fs::symlink_metadata` which translates to unix's `lstat` command which is ~10-15% faster that `stat` command as the former does not follow symlinks.
+- Program uses `fs::symlink_metadata` which translates to unix's `lstat` command which is ~10-15% faster that `stat` command as the former does not follow symlinks. Symlinks are reported as `NodeType::Symlink` nodes unless `follow_symlinks` is set, in which case only symlinks get the extra `stat` call.
 - Reducing `lstat` system call to only once per path.
 - Using `rayon` to parallely trigger tasks for each path.
 
@@ -19,15 +19,16 @@ This command is useful for scenarios where making a complete backup of complete
   - par-rayon - Parallellisation with rayon's `par_bridge` on `read_dir` iterator, recursive implementation. **[This gives results fastest]**.
 
 # Example
-```
+```no_run
 use win_tree::{build, Config, TreeNode};
 
 let source_path = ".";
 let tree_root: TreeNode = win_tree::build(win_tree::Config {
-    path: source_path,
+    path: source_path.to_string(),
     depth_check: Some(5),
     exclude_pattern: None,
     build_method: win_tree::BuildMethod::ParallelRayon,
+    follow_symlinks: false,
 })
 .expect("unable to build tree");
 ```
@@ -16,11 +16,39 @@ pub struct TreeNode {
     pub name: String,
     /// Indicates whether the node represents a file (`true`) or a directory (`false`).
     pub is_file: bool,
+    /// The type of the path the node was built from.
+    pub node_type: NodeType,
     /// The size of the file in bytes, if it's a file. `None` if it's a directory or if the size is not available.
+    /// Symlinks and special files have no data of their own so their size is `0`.
     pub size_in_bytes: Option<u64>,
     /// The children of the node, representing subdirectories and files.
     pub children: Vec<Arc<TreeNode>>,
 }
+/// Represents the type of the path a node was built from.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NodeType {
+    File,
+    Directory,
+    /// Symbolic link which was not followed, it never has children.
+    Symlink,
+    /// FIFOs, sockets and device nodes.
+    Special,
+}
+
+impl From<fs::FileType> for NodeType {
+    fn from(file_type: fs::FileType) -> Self {
+        if file_type.is_file() {
+            Self::File
+        } else if file_type.is_dir() {
+            Self::Directory
+        } else if file_type.is_symlink() {
+            Self::Symlink
+        } else {
+            Self::Special
+        }
+    }
+}
+
 /// Represents the method of building the tree. Usually serial-async and parallel-rayon are the most performant.
 /// Other methods are added just for benchmarking purposes.
 #[derive(Debug)]
@@ -56,6 +84,9 @@ pub struct Config {
     pub exclude_pattern: Option<String>,
     /// Method of building.
     pub build_method: BuildMethod,
+    /// Follow symlinks and build the node from their target instead. Links which are broken or point to one of their
+    /// own ancestors are kept as symlinks.
+    pub follow_symlinks: bool,
 }
 
 /// Builds a tree structure representing the directory structure starting from the specified path.
@@ -67,49 +98,137 @@ pub struct Config {
 /// # Returns
 ///
 /// A Result containing a TreeNode representing the root of the tree structure, or an io::Error if the operation fails.
+/// Any path of the tree which can not be read, or whose name is not valid UTF-8, fails the whole build.
 pub fn build(config: Config) -> Result<TreeNode, io::Error> {
+    let exclude_pattern = match &config.exclude_pattern {
+        Some(pattern) => {
+            Some(Regex::new(pattern).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?)
+        }
+        None => None,
+    };
     match config.build_method {
         BuildMethod::SerialAsync => block_on(_build(
             Path::new(&config.path),
             config.depth_check,
-            config.exclude_pattern.as_ref(),
+            exclude_pattern.as_ref(),
+            config.follow_symlinks,
             0,
+            &[],
         )),
         BuildMethod::ParallelRayon => _build_par(
             Path::new(&config.path),
             config.depth_check,
-            config.exclude_pattern.as_ref(),
+            exclude_pattern.as_ref(),
+            config.follow_symlinks,
             0,
+            &[],
         ),
     }
 }
 
+/// Identifies a directory regardless of the path it was reached through.
+#[cfg(unix)]
+type DirId = (u64, u64);
+#[cfg(not(unix))]
+type DirId = std::path::PathBuf;
+
+#[cfg(unix)]
+fn dir_id(_path: &Path, metadata: &fs::Metadata) -> DirId {
+    use std::os::unix::fs::MetadataExt;
+    (metadata.dev(), metadata.ino())
+}
+
+#[cfg(not(unix))]
+fn dir_id(path: &Path, _metadata: &fs::Metadata) -> DirId {
+    path.canonicalize().unwrap_or_else(|_| path.to_path_buf())
+}
+
+/// Metadata of the path, or of the target of a symlink if following them. A link is kept as it is if it is broken or
+/// its target is one of `ancestors`, the directories on the way to it, as following it would never end.
+fn metadata(
+    path: &Path,
+    follow_symlinks: bool,
+    ancestors: &[DirId],
+) -> Result<fs::Metadata, io::Error> {
+    let metadata = path.symlink_metadata()?;
+    if !follow_symlinks || !metadata.is_symlink() {
+        return Ok(metadata);
+    }
+    match path.metadata() {
+        Ok(target) if !target.is_dir() || !ancestors.contains(&dir_id(path, &target)) => Ok(target),
+        _ => Ok(metadata),
+    }
+}
+
+/// Directories on the way to the children of `dir`.
+fn child_ancestors(dir: &Path, metadata: &fs::Metadata, ancestors: &[DirId]) -> Vec<DirId> {
+    let mut ancestors = ancestors.to_vec();
+    ancestors.push(dir_id(dir, metadata));
+    ancestors
+}
+
+/// Name of the node of `path`, which for paths like `.` is the name of the directory they resolve to.
+fn node_name(path: &Path) -> Result<String, io::Error> {
+    let name = match path.file_name() {
+        Some(name) => name.to_os_string(),
+        None => {
+            let path = path.canonicalize()?;
+            path.file_name().unwrap_or(path.as_os_str()).to_os_string()
+        }
+    };
+    name.into_string().map_err(|name| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("name {name:?} is not valid UTF-8"),
+        )
+    })
+}
+
+/// Whether the name of `path` matches the exclude pattern.
+fn is_excluded(path: &Path, exclude_pattern: Option<&Regex>) -> Result<bool, io::Error> {
+    match exclude_pattern {
+        Some(pattern) => Ok(pattern.is_match(&node_name(path)?)),
+        None => Ok(false),
+    }
+}
+
+fn leaf_size(metadata: &fs::Metadata) -> Option<u64> {
+    match NodeType::from(metadata.file_type()) {
+        NodeType::File => Some(metadata.len()),
+        NodeType::Symlink | NodeType::Special => Some(0),
+        NodeType::Directory => None,
+    }
+}
+
 async fn _build(
     dir: &Path,
     depth_check: Option<u32>,
-    exclude_pattern: Option<&String>,
+    exclude_pattern: Option<&Regex>,
+    follow_symlinks: bool,
     depth: u32,
+    ancestors: &[DirId],
 ) -> Result<TreeNode, io::Error> {
     let mut children: Vec<Arc<TreeNode>> = vec![];
-    let mut total_size: Option<u64> = None;
-    let dir_metadata = dir.symlink_metadata()?;
-    if dir_metadata.is_file() {
-        total_size = Some(dir_metadata.len());
-    }
+    let dir_metadata = metadata(dir, follow_symlinks, ancestors)?;
+    let mut total_size = leaf_size(&dir_metadata);
     if dir_metadata.is_dir() && (depth_check.is_none() || depth < depth_check.unwrap()) {
         total_size = Some(0);
+        let ancestors = child_ancestors(dir, &dir_metadata, ancestors);
         for entry in fs::read_dir(dir)? {
             let entry = entry?.path();
             let entry = entry.as_path();
-            if exclude_pattern.is_some()
-                && Regex::new(exclude_pattern.unwrap())
-                    .unwrap()
-                    .is_match(entry.file_name().unwrap().to_str().unwrap())
-            {
+            if is_excluded(entry, exclude_pattern)? {
                 continue;
             }
-            let entry_node =
-                Box::pin(_build(entry, depth_check, exclude_pattern, depth + 1)).await?;
+            let entry_node = Box::pin(_build(
+                entry,
+                depth_check,
+                exclude_pattern,
+                follow_symlinks,
+                depth + 1,
+                &ancestors,
+            ))
+            .await?;
             // Calculate size only if each of the children also has a calculated size.
             total_size = match (total_size, entry_node.size_in_bytes) {
                 (Some(curr_size), Some(child_size)) => Some(curr_size + child_size),
@@ -118,46 +237,52 @@ async fn _build(
             children.push(Arc::new(entry_node));
         }
     }
-    return Ok(TreeNode {
-        name: String::from(dir.file_name().unwrap().to_str().unwrap()),
+    Ok(TreeNode {
+        name: node_name(dir)?,
         is_file: dir_metadata.is_file(),
+        node_type: NodeType::from(dir_metadata.file_type()),
         size_in_bytes: total_size,
         children,
-    });
+    })
 }
 
 fn _build_par(
     dir: &Path,
     depth_check: Option<u32>,
-    exclude_pattern: Option<&String>,
+    exclude_pattern: Option<&Regex>,
+    follow_symlinks: bool,
     depth: u32,
+    ancestors: &[DirId],
 ) -> Result<TreeNode, io::Error> {
-    let dir_metadata = dir.symlink_metadata()?;
+    let dir_metadata = metadata(dir, follow_symlinks, ancestors)?;
     let mut node = TreeNode {
-        name: String::from(dir.file_name().unwrap().to_str().unwrap()),
+        name: node_name(dir)?,
         is_file: dir_metadata.is_file(),
-        size_in_bytes: None,
+        node_type: NodeType::from(dir_metadata.file_type()),
+        size_in_bytes: leaf_size(&dir_metadata),
         children: vec![],
     };
-    if dir_metadata.is_file() {
-        node.size_in_bytes = Some(dir_metadata.len());
-    } else if dir_metadata.is_dir() && (depth_check.is_none() || depth < depth_check.unwrap()) {
+    if dir_metadata.is_dir() && (depth_check.is_none() || depth < depth_check.unwrap()) {
         node.size_in_bytes = Some(0);
+        let ancestors = child_ancestors(dir, &dir_metadata, ancestors);
+        let ancestors = ancestors.as_slice();
         let node_arc = Arc::new(Mutex::new(node));
         fs::read_dir(dir)?
             .par_bridge()
-            .filter(|e| e.is_ok())
-            .map(|e| e.unwrap().path())
-            .filter(|e| {
-                exclude_pattern.is_none()
-                    || !Regex::new(exclude_pattern.unwrap())
-                        .unwrap()
-                        .is_match(e.file_name().unwrap().to_str().unwrap())
-            })
             .map(|e| (e, Arc::clone(&node_arc)))
-            .for_each(move |(e, parent)| {
-                let entry_node =
-                    _build_par(e.as_path(), depth_check, exclude_pattern, depth + 1).unwrap();
+            .try_for_each(move |(e, parent)| -> Result<(), io::Error> {
+                let e = e?.path();
+                if is_excluded(&e, exclude_pattern)? {
+                    return Ok(());
+                }
+                let entry_node = _build_par(
+                    e.as_path(),
+                    depth_check,
+                    exclude_pattern,
+                    follow_symlinks,
+                    depth + 1,
+                    ancestors,
+                )?;
                 // Calculate size only if each of the children also has a calculated size.
                 {
                     let mut parent = parent.lock().unwrap();
@@ -167,7 +292,8 @@ fn _build_par(
                     };
                     parent.children.push(Arc::new(entry_node));
                 }
-            });
+                Ok(())
+            })?;
         node = Arc::try_unwrap(node_arc)
             .ok()
             .unwrap()